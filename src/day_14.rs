@@ -4,10 +4,12 @@ use std::error::Error;
 use std::str::FromStr;
 
 struct Polymer {
+    template: Vec<char>,
     first: char,
     last: char,
-    chain_pairs: HashMap<(char, char), usize>,
+    chain_pairs: PairCounts,
     rules: HashMap<(char, char), char>,
+    warnings: Vec<String>,
 }
 
 impl FromStr for Polymer {
@@ -17,99 +19,431 @@ impl FromStr for Polymer {
         let mut p = Polymer {
             chain_pairs: HashMap::new(),
             rules: HashMap::new(),
+            template: Vec::new(),
             first: Default::default(),
             last: Default::default(),
+            warnings: Vec::new(),
         };
-        let mut lines = s.lines();
+        let mut lines = s.lines().enumerate().map(|(i, l)| (i + 1, l));
         match lines.next() {
-            Some(l) => {
+            Some((_, l)) => {
                 let c: Vec<char> = l.chars().collect();
-                p.first = *c.first().unwrap();
+                p.first = *c.first().ok_or("line 1: empty template")?;
                 p.last = *c.last().unwrap();
+                p.template = c.clone();
                 for i in 0..(c.len() - 1) {
                     *p.chain_pairs.entry((c[i], c[i + 1])).or_default() += 1;
                 }
             }
-            None => return Err("invalid first line".into()),
+            None => return Err("empty input".into()),
         };
-        lines.next(); // Advance iterator once more to skip blank line.
-        let mut l: Option<&str>;
-        while {
-            l = lines.next();
-            l.is_some()
-        } {
-            let parts = l.unwrap().split(" -> ").collect::<Vec<_>>();
+        if let Some((n, l)) = lines.next() {
+            if !l.is_empty() {
+                return Err(format!("line {}: expected a blank line", n).into());
+            }
+        }
+        // The line each rule was first given on.
+        let mut rule_lines = HashMap::new();
+        for (n, l) in lines {
+            let parts = l.split(" -> ").collect::<Vec<_>>();
             if parts.len() != 2 {
-                return Err(format!("malformed line: {}", l.unwrap()).into());
+                return Err(format!("line {}: malformed rule: {}", n, l).into());
             }
             let sub_parts = parts[0].chars().collect::<Vec<_>>();
             if sub_parts.len() != 2 || parts[1].chars().count() != 1 {
-                return Err(format!("malformed line: {}", l.unwrap()).into());
+                return Err(format!("line {}: malformed rule: {}", n, l).into());
+            }
+            let pair = (sub_parts[0], sub_parts[1]);
+            let insert = parts[1].chars().next().unwrap();
+            match p.rules.get(&pair) {
+                Some(&prev) if prev != insert => {
+                    return Err(format!(
+                        "line {}: rule {} conflicts with {}{} -> {} on line {}",
+                        n, l, pair.0, pair.1, prev, rule_lines[&pair]
+                    )
+                    .into());
+                }
+                Some(_) => p.warnings.push(format!(
+                    "line {}: rule {} repeats line {}",
+                    n, l, rule_lines[&pair]
+                )),
+                None => {
+                    p.rules.insert(pair, insert);
+                    rule_lines.insert(pair, n);
+                }
+            }
+        }
+        // Pairs without a rule are left alone, which is allowed but probably a
+        // mistake in the input.
+        for (a, b) in p.reachable_pairs() {
+            if !p.rules.contains_key(&(a, b)) {
+                p.warnings.push(format!(
+                    "line 1: pair {}{} can form from the template but has no rule",
+                    a, b
+                ));
             }
-            p.rules.insert(
-                (sub_parts[0], sub_parts[1]),
-                parts[1].chars().next().unwrap(),
-            );
         }
         Ok(p)
     }
 }
 
+type PairCounts = HashMap<(char, char), u128>;
+
+// Element counts, most common first.
+type Histogram = Vec<(u128, char)>;
+
 impl Polymer {
-    fn advance_step(&mut self) {
-        let mut to_inc = Vec::new();
-        let mut to_dec = Vec::new();
-        for (&k, &v) in self.chain_pairs.iter() {
-            if self.rules.contains_key(&k) {
-                let m = self.rules[&k];
-                to_inc.push(((k.0, m), v));
-                to_inc.push(((m, k.1), v));
-                to_dec.push((k, v));
+    fn next_pairs(&self, pairs: &PairCounts) -> Option<PairCounts> {
+        let mut next = PairCounts::new();
+        for (&k, &v) in pairs.iter() {
+            let inserted = match self.rules.get(&k) {
+                Some(&m) => vec![(k.0, m), (m, k.1)],
+                None => vec![k],
+            };
+            for pair in inserted {
+                let count = next.entry(pair).or_default();
+                *count = count.checked_add(v)?;
             }
         }
-        for &(v, n) in to_inc.iter() {
-            *self.chain_pairs.entry(v).or_default() += n;
-        }
-        for &(v, n) in to_dec.iter() {
-            *self.chain_pairs.entry(v).or_default() -= n;
-        }
+        // Each element is counted in up to two pairs, so twice the total must fit too.
+        let total = next.values().try_fold(0u128, |t, &v| t.checked_add(v))?;
+        total.checked_mul(2)?;
+        Some(next)
     }
 
-    fn advance_steps(&mut self, n: usize) {
-        for _ in 0..n {
-            self.advance_step();
+    // Yields the element histogram of the template and then after every step.
+    fn histograms(&self) -> Histograms<'_> {
+        Histograms {
+            polymer: self,
+            pairs: Some(self.chain_pairs.clone()),
+            overflowed: false,
+            step: 0,
         }
     }
 
-    fn freq_count(&self) -> Vec<(usize, char)> {
-        let mut map: HashMap<char, usize> = HashMap::new();
-        for (&k, &v) in self.chain_pairs.iter() {
+    fn histogram_after(&self, steps: usize) -> Result<Histogram, Box<dyn Error>> {
+        // The iterator stops early at an overflow, leaving its error last.
+        self.histograms().take(steps + 1).last().unwrap()
+    }
+
+    fn freq_count(&self, pairs: &PairCounts) -> Histogram {
+        // Every element is counted in two pairs, except at the ends of the
+        // chain, which are counted once more here to make up for it.
+        let mut map: HashMap<char, u128> = HashMap::from([(self.first, 1)]);
+        *map.entry(self.last).or_default() += 1;
+        for (&k, &v) in pairs.iter() {
             *map.entry(k.0).or_default() += v;
             *map.entry(k.1).or_default() += v;
         }
 
         let mut heap = BinaryHeap::with_capacity(map.len());
         for (k, count) in map.into_iter() {
-            let mut c = count / 2;
-            if k == self.first || k == self.last {
-                c += 1;
-            }
-            heap.push(Reverse((c, k)));
+            heap.push(Reverse((count / 2, k)));
         }
         heap.into_sorted_vec().into_iter().map(|r| r.0).collect()
     }
 }
 
+struct Histograms<'a> {
+    polymer: &'a Polymer,
+    pairs: Option<PairCounts>,
+    overflowed: bool,
+    step: usize,
+}
+
+impl Iterator for Histograms<'_> {
+    type Item = Result<Histogram, Box<dyn Error>>;
+
+    // Yields an error once the counts no longer fit, and nothing after it.
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pairs) = self.pairs.take() {
+            let histogram = self.polymer.freq_count(&pairs);
+            self.pairs = self.polymer.next_pairs(&pairs);
+            self.overflowed = self.pairs.is_none();
+            self.step += 1;
+            return Some(Ok(histogram));
+        }
+        if self.overflowed {
+            self.overflowed = false;
+            let msg = format!("element counts overflow at step {}", self.step);
+            return Some(Err(msg.into()));
+        }
+        None
+    }
+}
+
+type Matrix = Vec<Vec<u64>>;
+
+fn mat_mul(a: &Matrix, b: &Matrix, modulus: u64) -> Matrix {
+    let n = a.len();
+    let mut c = vec![vec![0; n]; n];
+    for i in 0..n {
+        for k in 0..n {
+            if a[i][k] == 0 {
+                continue;
+            }
+            for j in 0..n {
+                c[i][j] = ((c[i][j] as u128 + a[i][k] as u128 * b[k][j] as u128) % modulus as u128)
+                    as u64;
+            }
+        }
+    }
+    c
+}
+
+impl Polymer {
+    // Every pair the chain can ever contain, in a fixed order.
+    fn reachable_pairs(&self) -> Vec<(char, char)> {
+        let mut pairs: Vec<_> = self.chain_pairs.keys().copied().collect();
+        pairs.sort_unstable();
+        let mut i = 0;
+        while i < pairs.len() {
+            let k = pairs[i];
+            if let Some(&m) = self.rules.get(&k) {
+                for pair in [(k.0, m), (m, k.1)] {
+                    if !pairs.contains(&pair) {
+                        pairs.push(pair);
+                    }
+                }
+            }
+            i += 1;
+        }
+        pairs
+    }
+
+    // Element counts modulo `modulus` after `steps` steps, in alphabetical
+    // order. A step maps the pair counts linearly, so `steps` of them amount to
+    // multiplying by a power of the step's matrix, found by repeated squaring.
+    fn histogram_mod(&self, steps: u64, modulus: u64) -> Result<Vec<(u64, char)>, Box<dyn Error>> {
+        // Halving the doubled element counts needs 2 to be invertible.
+        if modulus.is_multiple_of(2) {
+            return Err("modulus must be odd".into());
+        }
+        let pairs = self.reachable_pairs();
+        let index: HashMap<_, _> = pairs.iter().enumerate().map(|(i, &p)| (p, i)).collect();
+        let n = pairs.len();
+        // Row i holds the pairs that pair i becomes after one step.
+        let mut step = vec![vec![0; n]; n];
+        for (i, &k) in pairs.iter().enumerate() {
+            match self.rules.get(&k) {
+                Some(&m) => {
+                    step[i][index[&(k.0, m)]] += 1;
+                    step[i][index[&(m, k.1)]] += 1;
+                }
+                None => step[i][i] += 1,
+            }
+        }
+        for row in step.iter_mut() {
+            for v in row.iter_mut() {
+                *v %= modulus;
+            }
+        }
+        let mut counts = vec![0; n];
+        for (k, &v) in self.chain_pairs.iter() {
+            counts[index[k]] = (v % modulus as u128) as u64;
+        }
+        let mut power = step;
+        let mut remaining = steps;
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                counts = (0..n)
+                    .map(|j| {
+                        let c = (0..n)
+                            .map(|i| counts[i] as u128 * power[i][j] as u128 % modulus as u128)
+                            .sum::<u128>();
+                        (c % modulus as u128) as u64
+                    })
+                    .collect();
+            }
+            remaining >>= 1;
+            if remaining > 0 {
+                power = mat_mul(&power, &power, modulus);
+            }
+        }
+
+        // The ends of the chain are only counted in one pair each.
+        let mut map: HashMap<char, u128> = HashMap::from([(self.first, 1)]);
+        *map.entry(self.last).or_default() += 1;
+        for (&k, &v) in pairs.iter().zip(counts.iter()) {
+            *map.entry(k.0).or_default() += v as u128;
+            *map.entry(k.1).or_default() += v as u128;
+        }
+        let half = modulus.div_ceil(2) as u128;
+        let mut histogram: Vec<_> = (map.into_iter())
+            .map(|(k, count)| ((count % modulus as u128 * half % modulus as u128) as u64, k))
+            .collect();
+        histogram.sort_unstable_by_key(|&(_, k)| k);
+        Ok(histogram)
+    }
+}
+
+// Longest chain `materialise` will build.
+const MATERIALISE_LIMIT: usize = 1 << 24;
+
+impl Polymer {
+    // Builds the actual chain after `steps` steps, and checks its element
+    // counts against those derived from the pair counts.
+    fn materialise(&self, steps: usize) -> Result<String, Box<dyn Error>> {
+        let mut chain = self.template.clone();
+        for step in 1..=steps {
+            let mut next = Vec::with_capacity(chain.len() * 2);
+            for w in chain.windows(2) {
+                next.push(w[0]);
+                if let Some(&m) = self.rules.get(&(w[0], w[1])) {
+                    next.push(m);
+                }
+            }
+            next.extend(chain.last());
+            if next.len() > MATERIALISE_LIMIT {
+                return Err(format!(
+                    "polymer after step {} is {} elements long, over the limit of {}",
+                    step,
+                    next.len(),
+                    MATERIALISE_LIMIT
+                )
+                .into());
+            }
+            chain = next;
+        }
+
+        let mut counts: HashMap<char, u128> = HashMap::new();
+        for &c in chain.iter() {
+            *counts.entry(c).or_default() += 1;
+        }
+        for (n, c) in self.histogram_after(steps)? {
+            let actual = counts.remove(&c).unwrap_or(0);
+            if actual != n {
+                let msg = format!(
+                    "polymer has {} {} but the pair counts give {}",
+                    actual, c, n
+                );
+                return Err(msg.into());
+            }
+        }
+        if let Some((c, n)) = counts.into_iter().next() {
+            return Err(format!("polymer has {} {} but the pair counts give none", n, c).into());
+        }
+        Ok(chain.into_iter().collect())
+    }
+}
+
+fn spread(histogram: &Histogram) -> u128 {
+    histogram.first().map_or(0, |h| h.0) - histogram.last().map_or(0, |h| h.0)
+}
+
 pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
-    let mut polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
-    polymer.advance_steps(10);
-    let counts = polymer.freq_count();
-    Ok((counts.iter().next().unwrap().0 - counts.iter().next_back().unwrap().0).to_string())
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    Ok(spread(&polymer.histogram_after(10)?).to_string())
 }
 
 pub fn prob_2(input: &str) -> Result<String, Box<dyn Error>> {
-    let mut polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
-    polymer.advance_steps(40);
-    let counts = polymer.freq_count();
-    Ok((counts.iter().next().unwrap().0 - counts.iter().next_back().unwrap().0).to_string())
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    Ok(spread(&polymer.histogram_after(40)?).to_string())
+}
+
+pub fn steps(input: &str, steps: usize) -> Result<String, Box<dyn Error>> {
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    Ok(format!("{}\n", spread(&polymer.histogram_after(steps)?)))
+}
+
+// Prints the element histogram after every step up to `steps`, followed by the
+// difference between the most and least common elements.
+pub fn histograms(input: &str, steps: usize) -> Result<String, Box<dyn Error>> {
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    let mut out = String::new();
+    let mut last = Histogram::new();
+    for (step, histogram) in polymer.histograms().take(steps + 1).enumerate() {
+        let histogram = histogram?;
+        let counts = (histogram.iter())
+            .map(|(n, c)| format!("{}={}", c, n))
+            .collect::<Vec<_>>();
+        out += &format!("step {}: {}\n", step, counts.join(" "));
+        last = histogram;
+    }
+    out += &format!("answer: {}\n", spread(&last));
+    Ok(out)
+}
+
+// Prints each element's count modulo `modulus` after `steps` steps.
+pub fn counts_mod(input: &str, steps: u64, modulus: u64) -> Result<String, Box<dyn Error>> {
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    Ok((polymer.histogram_mod(steps, modulus)?.iter())
+        .map(|(n, c)| format!("{}: {}\n", c, n))
+        .collect())
+}
+
+pub fn materialise(input: &str, steps: usize) -> Result<String, Box<dyn Error>> {
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    Ok(format!("{}\n", polymer.materialise(steps)?))
+}
+
+// Lists any problems with the input that don't stop it being used.
+pub fn check(input: &str) -> Result<String, Box<dyn Error>> {
+    let polymer: Polymer = std::fs::read_to_string(input)?.parse()?;
+    if polymer.warnings.is_empty() {
+        return Ok("no problems found\n".to_string());
+    }
+    Ok((polymer.warnings.iter())
+        .map(|w| format!("warning: {}\n", w))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = "\n\nAA -> B\nAB -> A\nBA -> B\nBB -> A\n";
+
+    #[test]
+    fn one_letter_template() {
+        let polymer: Polymer = format!("A{}", RULES).parse().unwrap();
+        assert_eq!(polymer.materialise(0).unwrap(), "A");
+        assert_eq!(polymer.materialise(3).unwrap(), "A");
+        assert_eq!(polymer.histogram_after(3).unwrap(), vec![(1, 'A')]);
+        assert_eq!(polymer.histogram_mod(0, 7).unwrap(), vec![(1, 'A')]);
+    }
+
+    #[test]
+    fn template_starting_and_ending_with_same_element() {
+        let polymer: Polymer = format!("ABA{}", RULES).parse().unwrap();
+        assert_eq!(polymer.materialise(1).unwrap(), "AABBA");
+        assert_eq!(polymer.materialise(2).unwrap(), "ABAABABBA");
+        for steps in 0..8 {
+            let chain = polymer.materialise(steps).unwrap();
+            let a = chain.chars().filter(|&c| c == 'A').count() as u64;
+            let b = chain.len() as u64 - a;
+            let expected = vec![(a, 'A'), (b, 'B')];
+            assert_eq!(
+                polymer.histogram_mod(steps as u64, 1_000_000_007).unwrap(),
+                expected
+            );
+        }
+    }
+
+    fn parse_error(s: &str) -> String {
+        s.parse::<Polymer>().err().unwrap().to_string()
+    }
+
+    #[test]
+    fn rejects_empty_template() {
+        assert_eq!(parse_error(RULES), "line 1: empty template");
+    }
+
+    #[test]
+    fn rejects_conflicting_rules() {
+        let input = format!("AB{}AB -> B\n", RULES);
+        assert_eq!(
+            parse_error(&input),
+            "line 7: rule AB -> B conflicts with AB -> A on line 4"
+        );
+    }
+
+    #[test]
+    fn warns_about_duplicate_rules() {
+        let polymer: Polymer = format!("AB{}AB -> A\n", RULES).parse().unwrap();
+        assert_eq!(
+            polymer.warnings,
+            vec!["line 7: rule AB -> A repeats line 4"]
+        );
+    }
 }
@@ -1,336 +1,420 @@
-use std::any::Any;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
 use std::error::Error;
-use std::fmt::{self, Debug};
+use std::fmt;
 use std::ops::{Add, AddAssign};
 use std::str::FromStr;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum SnailfishTraversePath {
-    LEFT,
-    RIGHT,
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+struct RegularNumber {
+    value: u32,
+    // Number of pairs this regular number is nested inside.
+    depth: u32,
 }
 
-struct SnailfishNumberPtr<'a> {
-    val: &'a mut SnailfishNumber,
-    path: VecDeque<SnailfishTraversePath>,
-    depth: u8,
-}
-
-trait SnailfishValue: Debug {
-    fn is_number(&self) -> bool;
-    fn magnitude(&self) -> u64;
-    fn as_any(&self) -> &dyn Any;
-    fn as_any_mut(&mut self) -> &mut dyn Any;
-    fn get_numbers(&mut self) -> Vec<SnailfishNumberPtr>;
-}
-
-impl Clone for Box<dyn SnailfishValue> {
-    fn clone(&self) -> Self {
-        let val = self.as_any();
-        if self.is_number() {
-            return Box::new(val.downcast_ref::<SnailfishNumber>().unwrap().clone());
-        } else {
-            let pair = val.downcast_ref::<SnailfishPair>().unwrap();
-            return Box::new(SnailfishPair {
-                left: pair.left.clone(),
-                right: pair.right.clone(),
-            });
-        }
-    }
+// A snailfish number stored as its regular numbers from left to right. Since
+// every pair has exactly two elements, the depths alone are enough to recover
+// the nesting of the pairs.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct SnailfishNumber {
+    regulars: Vec<RegularNumber>,
 }
 
-impl Add for Box<dyn SnailfishValue> {
+impl Add for SnailfishNumber {
     type Output = Self;
 
-    fn add(self, rhs: Self) -> Self {
-        let mut p = SnailfishPair {
-            left: self.clone(),
-            right: rhs,
-        };
-        p.reduce();
-        Box::new(p)
+    fn add(mut self, rhs: Self) -> Self {
+        self += rhs;
+        self
     }
 }
 
-impl AddAssign for Box<dyn SnailfishValue> {
+impl AddAssign for SnailfishNumber {
     fn add_assign(&mut self, rhs: Self) {
-        *self = self.clone() + rhs;
+        self.regulars.extend(rhs.regulars);
+        for r in self.regulars.iter_mut() {
+            r.depth += 1;
+        }
+        self.reduce();
     }
 }
 
-#[derive(Copy, Clone, Default)]
-struct SnailfishNumber {
-    num: u8,
-}
-
-impl SnailfishValue for SnailfishNumber {
-    fn is_number(&self) -> bool {
-        true
-    }
-
-    fn magnitude(&self) -> u64 {
-        self.num as u64
-    }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
-    }
-
-    fn get_numbers(&mut self) -> Vec<SnailfishNumberPtr> {
-        vec![SnailfishNumberPtr {
-            val: self,
-            path: VecDeque::new(),
-            depth: 0,
-        }]
-    }
+// A single step of reducing a snailfish number, identifying the pair that
+// exploded by the left/right turns taken to reach it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Reduction {
+    Explode { path: String },
+    Split { value: u32 },
 }
 
-impl Debug for SnailfishNumber {
+impl fmt::Display for Reduction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.num)
+        match self {
+            Reduction::Explode { path } => write!(f, "explode at {}", path),
+            Reduction::Split { value } => write!(f, "split {}", value),
+        }
     }
 }
 
-#[derive(Clone)]
-struct SnailfishPair {
-    left: Box<dyn SnailfishValue>,
-    right: Box<dyn SnailfishValue>,
+// Recursive-descent parser for snailfish numbers. Errors report the 1-based
+// column of the offending character.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    regulars: Vec<RegularNumber>,
 }
 
-impl SnailfishValue for SnailfishPair {
-    fn is_number(&self) -> bool {
-        false
+impl Parser {
+    fn unexpected(&self, expected: &str) -> Box<dyn Error> {
+        let col = self.pos + 1;
+        match self.chars.get(self.pos) {
+            Some(c) => format!("expected {} but found '{}' at column {}", expected, c, col).into(),
+            None => format!("expected {} but input ended at column {}", expected, col).into(),
+        }
     }
 
-    fn magnitude(&self) -> u64 {
-        (3 * self.left.magnitude()) + (2 * self.right.magnitude())
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
+    fn expect(&mut self, expected: char) -> Result<(), Box<dyn Error>> {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) != Some(&expected) {
+            return Err(self.unexpected(&format!("'{}'", expected)));
+        }
+        self.pos += 1;
+        Ok(())
     }
 
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
+    fn pair(&mut self, depth: u32) -> Result<(), Box<dyn Error>> {
+        self.expect('[')?;
+        self.element(depth + 1)?;
+        self.expect(',')?;
+        self.element(depth + 1)?;
+        self.expect(']')
     }
 
-    fn get_numbers(&mut self) -> Vec<SnailfishNumberPtr> {
-        let mut v1 = self.left.get_numbers();
-        for i in 0..v1.len() {
-            v1[i].path.push_front(SnailfishTraversePath::LEFT);
-            v1[i].depth += 1;
+    fn element(&mut self, depth: u32) -> Result<(), Box<dyn Error>> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some('[') => self.pair(depth),
+            Some(c) if c.is_ascii_digit() => self.regular(depth),
+            _ => Err(self.unexpected("'[' or a regular number")),
         }
-        let mut v2 = self.right.get_numbers();
-        for i in 0..v2.len() {
-            v2[i].path.push_front(SnailfishTraversePath::RIGHT);
-            v2[i].depth += 1;
+    }
+
+    fn regular(&mut self, depth: u32) -> Result<(), Box<dyn Error>> {
+        let start = self.pos;
+        let mut value: u32 = 0;
+        while let Some(d) = self.chars.get(self.pos).and_then(|c| c.to_digit(10)) {
+            value = match value.checked_mul(10).and_then(|v| v.checked_add(d)) {
+                Some(v) => v,
+                None => {
+                    return Err(
+                        format!("regular number at column {} is too large", start + 1).into(),
+                    )
+                }
+            };
+            self.pos += 1;
         }
-        v1.append(&mut v2);
-        v1
+        self.regulars.push(RegularNumber { value, depth });
+        Ok(())
     }
 }
 
-impl FromStr for SnailfishPair {
+impl FromStr for SnailfishNumber {
     type Err = Box<dyn Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let err_invalid_input = Err("invalid input".into());
-        let left: Box<dyn SnailfishValue>;
-        let right: Box<dyn SnailfishValue>;
-        let mut chars = s.chars();
-        if chars.next().unwrap() != '[' {
-            return err_invalid_input;
-        }
-        let c = chars.next().unwrap();
-        if c != '[' {
-            let v = c.to_string().parse::<u8>()?;
-            left = Box::new(SnailfishNumber { num: v });
-        } else {
-            let mut bracket_count = 1;
-            let mut substr = c.to_string();
-            while bracket_count > 0 {
-                let c = chars.next().unwrap();
-                substr.push(c);
-                match c {
-                    '[' => bracket_count += 1,
-                    ']' => bracket_count -= 1,
-                    _ => (),
-                }
-            }
-            left = Box::new(substr.parse::<SnailfishPair>()?);
-        }
-        if chars.next().unwrap() != ',' {
-            return err_invalid_input;
-        }
-        let c = chars.next().unwrap();
-        if c != '[' {
-            let v = c.to_string().parse::<u8>()?;
-            right = Box::new(SnailfishNumber { num: v });
-        } else {
-            let mut bracket_count = 1;
-            let mut substr = c.to_string();
-            while bracket_count > 0 {
-                let c = chars.next().unwrap();
-                substr.push(c);
-                match c {
-                    '[' => bracket_count += 1,
-                    ']' => bracket_count -= 1,
-                    _ => (),
-                }
-            }
-            right = Box::new(substr.parse::<SnailfishPair>()?);
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+            regulars: Vec::new(),
+        };
+        parser.pair(0)?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.unexpected("end of input"));
         }
-        Ok(SnailfishPair {
-            left: left,
-            right: right,
+        Ok(SnailfishNumber {
+            regulars: parser.regulars,
         })
     }
 }
 
-impl Debug for SnailfishPair {
+impl fmt::Display for SnailfishNumber {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "[{:?},{:?}]", self.left, self.right)
+        let mut i = 0;
+        self.fmt_element(f, &mut i, 0)
     }
 }
 
-impl SnailfishPair {
-    fn get(&mut self, path: &VecDeque<SnailfishTraversePath>) -> &mut dyn SnailfishValue {
-        let mut v: &mut dyn SnailfishValue = self;
-        for p in path.iter() {
-            match p {
-                SnailfishTraversePath::LEFT => {
-                    v = &mut *v.as_any_mut().downcast_mut::<SnailfishPair>().unwrap().left
-                }
-                SnailfishTraversePath::RIGHT => {
-                    v = &mut *v
-                        .as_any_mut()
-                        .downcast_mut::<SnailfishPair>()
-                        .unwrap()
-                        .right
+impl SnailfishNumber {
+    // Writes the element at `depth` that starts with the `i`th regular number:
+    // either that regular number itself or a pair that contains it.
+    fn fmt_element(&self, f: &mut fmt::Formatter, i: &mut usize, depth: u32) -> fmt::Result {
+        let r = self.regulars[*i];
+        if r.depth == depth {
+            *i += 1;
+            return write!(f, "{}", r.value);
+        }
+        write!(f, "[")?;
+        self.fmt_element(f, i, depth + 1)?;
+        write!(f, ",")?;
+        self.fmt_element(f, i, depth + 1)?;
+        write!(f, "]")
+    }
+
+    fn magnitude(&self) -> u64 {
+        // Collapse pairs as soon as both of their elements are known, which is
+        // when the top two entries of the stack are at the same depth.
+        let mut stack: Vec<(u64, u32)> = Vec::with_capacity(self.regulars.len());
+        for r in self.regulars.iter() {
+            let (mut value, mut depth) = (r.value as u64, r.depth);
+            while let Some(&(left, d)) = stack.last() {
+                if d != depth {
+                    break;
                 }
+                stack.pop();
+                value = 3 * left + 2 * value;
+                depth -= 1;
             }
+            stack.push((value, depth));
         }
-        v
+        stack.first().map_or(0, |&(value, _)| value)
     }
 
-    fn reduce(&mut self) {
-        loop {
-            if self.explode() {
-                continue;
+    // The turns taken from the outermost pair to reach the `i`th regular number.
+    fn path(&self, i: usize) -> String {
+        let mut path: Vec<&str> = Vec::new();
+        for (j, r) in self.regulars.iter().take(i + 1).enumerate() {
+            // Step back up past every right turn, then take the right turn at
+            // the last pair still open.
+            if j > 0 {
+                while path.last() == Some(&"R") {
+                    path.pop();
+                }
+                if let Some(last) = path.last_mut() {
+                    *last = "R";
+                }
             }
-            if self.split() {
-                continue;
+            while (path.len() as u32) < r.depth {
+                path.push("L");
             }
-            break;
         }
+        path.join("/")
     }
 
-    fn explode(&mut self) -> bool {
-        let mut nums = self.get_numbers();
-        for i in 0..(nums.len() - 1) {
-            if nums[i].depth > 4 {
-                let mut p1 = nums[i].path.clone();
-                p1.pop_back(); // Remove last element to get parent.
-                let mut p2 = nums[i + 1].path.clone();
-                p2.pop_back(); // Remove last element to get parent.
-                if p1 == p2 {
-                    if i > 0 {
-                        nums[i - 1].val.num += nums[i].val.num;
-                    }
-                    if i + 2 < nums.len() {
-                        nums[i + 2].val.num += nums[i + 1].val.num;
-                    }
-                    let pos = p1.pop_back().unwrap();
-                    let parent = self
-                        .get(&p1)
-                        .as_any_mut()
-                        .downcast_mut::<SnailfishPair>()
-                        .unwrap();
-                    match pos {
-                        SnailfishTraversePath::LEFT => {
-                            parent.left = Box::new(SnailfishNumber { num: 0 });
-                        }
-                        SnailfishTraversePath::RIGHT => {
-                            parent.right = Box::new(SnailfishNumber { num: 0 });
-                        }
-                    }
-                    return true;
-                }
-            }
+    fn reduce(&mut self) {
+        while self.explode().is_some() || self.split().is_some() {}
+    }
+
+    // Reduces the number like `reduce`, recording each step along with the
+    // number that it results in.
+    fn reduce_traced(&mut self) -> Vec<(Reduction, SnailfishNumber)> {
+        let mut trace = Vec::new();
+        loop {
+            let step = if let Some(i) = self.explode() {
+                // The exploded pair is replaced by the regular number at `i`.
+                Reduction::Explode { path: self.path(i) }
+            } else if let Some(value) = self.split() {
+                Reduction::Split { value }
+            } else {
+                break;
+            };
+            trace.push((step, self.clone()));
         }
-        false
+        trace
     }
 
-    fn split(&mut self) -> bool {
-        let nums = self.get_numbers();
-        for n in nums.iter() {
-            if n.val.num > 9 {
-                let left = n.val.num / 2;
-                let right = n.val.num - left;
-                let mut p = n.path.clone();
-                let pos = p.pop_back().unwrap();
-                let parent = self
-                    .get(&p)
-                    .as_any_mut()
-                    .downcast_mut::<SnailfishPair>()
-                    .unwrap();
-                match pos {
-                    SnailfishTraversePath::LEFT => {
-                        parent.left = Box::new(SnailfishPair {
-                            left: Box::new(SnailfishNumber { num: left }),
-                            right: Box::new(SnailfishNumber { num: right }),
-                        });
-                    }
-                    SnailfishTraversePath::RIGHT => {
-                        parent.right = Box::new(SnailfishPair {
-                            left: Box::new(SnailfishNumber { num: left }),
-                            right: Box::new(SnailfishNumber { num: right }),
-                        });
-                    }
-                }
-                return true;
-            }
+    // Returns the index of the leftmost regular number of the exploded pair.
+    fn explode(&mut self) -> Option<usize> {
+        // The first deeply nested regular number followed by one at the same
+        // depth is the left element of the leftmost pair of regular numbers
+        // nested inside four pairs.
+        let i = (0..self.regulars.len().saturating_sub(1)).find(|&i| {
+            self.regulars[i].depth > 4 && self.regulars[i].depth == self.regulars[i + 1].depth
+        })?;
+        let (left, right) = (self.regulars[i], self.regulars[i + 1]);
+        if i > 0 {
+            self.regulars[i - 1].value += left.value;
+        }
+        if i + 2 < self.regulars.len() {
+            self.regulars[i + 2].value += right.value;
         }
-        false
+        self.regulars[i] = RegularNumber {
+            value: 0,
+            depth: left.depth - 1,
+        };
+        self.regulars.remove(i + 1);
+        Some(i)
     }
+
+    // Returns the value of the regular number that was split.
+    fn split(&mut self) -> Option<u32> {
+        let i = self.regulars.iter().position(|r| r.value > 9)?;
+        let r = self.regulars[i];
+        let left = RegularNumber {
+            value: r.value / 2,
+            depth: r.depth + 1,
+        };
+        let right = RegularNumber {
+            value: r.value - left.value,
+            depth: r.depth + 1,
+        };
+        self.regulars[i] = left;
+        self.regulars.insert(i + 1, right);
+        Some(r.value)
+    }
+}
+
+fn parse_numbers(list: &str) -> Result<Vec<SnailfishNumber>, Box<dyn Error>> {
+    list.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            l.parse()
+                .map_err(|e| format!("line {}: {}", i + 1, e).into())
+        })
+        .collect()
+}
+
+// The magnitude of adding the `left`th number in a list to the `right`th.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct PairSum {
+    magnitude: u64,
+    left: usize,
+    right: usize,
+}
+
+// Finds the `k` ordered pairs of different numbers with the largest sums,
+// largest first. Each thread takes every nth left operand and keeps its own
+// top `k`, which are merged at the end.
+fn top_pairs(numbers: &[SnailfishNumber], k: usize) -> Vec<PairSum> {
+    // There are never more than this many pairs to report.
+    let k = k.min(numbers.len() * numbers.len().saturating_sub(1));
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .clamp(1, numbers.len().max(1));
+    // Larger magnitudes sort first, with ties going to the earlier pair.
+    let rank = |p: &PairSum| (Reverse(p.magnitude), p.left, p.right);
+    let mut best: Vec<PairSum> = std::thread::scope(|scope| {
+        let handles = (0..threads)
+            .map(|t| {
+                scope.spawn(move || {
+                    let mut best: Vec<PairSum> = Vec::with_capacity(k.saturating_add(1));
+                    for (i, a) in numbers.iter().enumerate().skip(t).step_by(threads) {
+                        for (j, b) in numbers.iter().enumerate() {
+                            if i == j {
+                                continue;
+                            }
+                            let p = PairSum {
+                                magnitude: (a.clone() + b.clone()).magnitude(),
+                                left: i,
+                                right: j,
+                            };
+                            let pos = best.partition_point(|q| rank(q) < rank(&p));
+                            if pos < k {
+                                best.insert(pos, p);
+                                best.truncate(k);
+                            }
+                        }
+                    }
+                    best
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+    best.sort_unstable_by_key(rank);
+    best.truncate(k);
+    best
 }
 
 pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
     let list = std::fs::read_to_string(input)?;
-    let mut lines = list.lines();
-    let mut sum: Box<dyn SnailfishValue> =
-        Box::new(lines.next().unwrap().parse::<SnailfishPair>()?);
-    let mut next;
-    while {
-        next = lines.next();
-        next.is_some()
-    } {
-        let pair: Box<dyn SnailfishValue> = Box::new(next.unwrap().parse::<SnailfishPair>()?);
-        sum += pair;
+    let mut numbers = parse_numbers(&list)?.into_iter();
+    let mut sum = numbers.next().ok_or("empty input")?;
+    for n in numbers {
+        sum += n;
     }
     Ok(sum.magnitude().to_string())
 }
 
 pub fn prob_2(input: &str) -> Result<String, Box<dyn Error>> {
     let list = std::fs::read_to_string(input)?;
-    let pairs: Vec<_> = list
-        .lines()
-        .map(|l| l.parse::<SnailfishPair>().unwrap())
-        .collect();
-    let mut max_magnitude = 0;
-    for i in 0..pairs.len() {
-        for j in 0..pairs.len() {
-            if i != j {
-                let p1: Box<dyn SnailfishValue> = Box::new(pairs[i].clone());
-                let p2: Box<dyn SnailfishValue> = Box::new(pairs[j].clone());
-                max_magnitude = max_magnitude.max((p1 + p2).magnitude());
-            }
-        }
+    let numbers = parse_numbers(&list)?;
+    // The winning pair is listed below the answer.
+    match top_pairs(&numbers, 1).first() {
+        Some(p) => Ok(format!("{}\n{}", p.magnitude, describe_pair(p, &numbers))),
+        None => Ok(0.to_string()),
+    }
+}
+
+fn describe_pair(p: &PairSum, numbers: &[SnailfishNumber]) -> String {
+    format!(
+        "#{} {} + #{} {}",
+        p.left, numbers[p.left], p.right, numbers[p.right]
+    )
+}
+
+// Lists the `k` pairs of numbers with the largest sums, along with their
+// indices in the list.
+pub fn top_sums(input: &str, k: usize) -> Result<String, Box<dyn Error>> {
+    let list = std::fs::read_to_string(input)?;
+    let numbers = parse_numbers(&list)?;
+    let mut out = String::new();
+    for p in top_pairs(&numbers, k) {
+        out += &format!("{}: {}\n", p.magnitude, describe_pair(&p, &numbers));
+    }
+    Ok(out)
+}
+
+// Adds two snailfish numbers, listing every step taken to reduce the sum.
+pub fn trace(a: &str, b: &str) -> Result<String, Box<dyn Error>> {
+    let a: SnailfishNumber = a.parse()?;
+    let b: SnailfishNumber = b.parse()?;
+    let mut sum = SnailfishNumber {
+        regulars: a.regulars.into_iter().chain(b.regulars).collect(),
+    };
+    for r in sum.regulars.iter_mut() {
+        r.depth += 1;
+    }
+    let mut out = format!("after addition: {}\n", sum);
+    for (step, n) in sum.reduce_traced() {
+        out += &format!("after {}: {}\n", step, n);
+    }
+    out += &format!("magnitude: {}\n", sum.magnitude());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_column_of_parse_error() {
+        let err = "[1,,2]".parse::<SnailfishNumber>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected '[' or a regular number but found ',' at column 4"
+        );
+    }
+
+    #[test]
+    fn display_round_trips_spaced_input() {
+        let n: SnailfishNumber = "[ [10, 200] , 3 ]".parse().unwrap();
+        assert_eq!(n.to_string(), "[[10,200],3]");
+        assert_eq!(
+            n.to_string()
+                .parse::<SnailfishNumber>()
+                .unwrap()
+                .to_string(),
+            n.to_string()
+        );
     }
-    Ok(max_magnitude.to_string())
 }
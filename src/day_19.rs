@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::fmt::{self, Debug};
@@ -5,68 +6,77 @@ use std::ops::{Add, AddAssign, Sub, SubAssign};
 use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
-enum Rotation {
-    None,
-    Heading90,
-    Heading180,
-    Heading270,
-    Altitude90,
-    Altitude90Heading90,
-    Altitude90Heading180,
-    Altitude90Heading270,
-    Altitude270,
-    Altitude270Heading90,
-    Altitude270Heading180,
-    Altitude270Heading270,
-    Bank90,
-    Bank90Heading90,
-    Bank90Heading180,
-    Bank90Heading270,
-    Bank180,
-    Bank180Heading90,
-    Bank180Heading180,
-    Bank180Heading270,
-    Bank270,
-    Bank270Heading90,
-    Bank270Heading180,
-    Bank270Heading270,
-}
-
-#[derive(Copy, Clone, Eq, Hash, PartialEq)]
 struct RotationMatrix([[i32; 3]; 3]);
 
 impl RotationMatrix {
-    // Reference: https://www.euclideanspace.com/maths/algebra/matrix/transforms/examples/index.htm
-    fn from_rot(r: Rotation) -> Self {
-        let m;
-        match r {
-            Rotation::None => m = [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
-            Rotation::Heading90 => m = [[0, 0, 1], [0, 1, 0], [-1, 0, 0]],
-            Rotation::Heading180 => m = [[-1, 0, 0], [0, 1, 0], [0, 0, -1]],
-            Rotation::Heading270 => m = [[0, 0, -1], [0, 1, 0], [1, 0, 0]],
-            Rotation::Altitude90 => m = [[0, -1, 0], [1, 0, 0], [0, 0, 1]],
-            Rotation::Altitude90Heading90 => m = [[0, 0, 1], [1, 0, 0], [0, 1, 0]],
-            Rotation::Altitude90Heading180 => m = [[0, 1, 0], [1, 0, 0], [0, 0, -1]],
-            Rotation::Altitude90Heading270 => m = [[0, 0, -1], [1, 0, 0], [0, -1, 0]],
-            Rotation::Altitude270 => m = [[0, 1, 0], [-1, 0, 0], [0, 0, 1]],
-            Rotation::Altitude270Heading90 => m = [[0, 0, 1], [-1, 0, 0], [0, -1, 0]],
-            Rotation::Altitude270Heading180 => m = [[0, -1, 0], [-1, 0, 0], [0, 0, -1]],
-            Rotation::Altitude270Heading270 => m = [[0, 0, -1], [-1, 0, 0], [0, 1, 0]],
-            Rotation::Bank90 => m = [[1, 0, 0], [0, 0, -1], [0, 1, 0]],
-            Rotation::Bank90Heading90 => m = [[0, 1, 0], [0, 0, -1], [-1, 0, 0]],
-            Rotation::Bank90Heading180 => m = [[-1, 0, 0], [0, 0, -1], [0, -1, 0]],
-            Rotation::Bank90Heading270 => m = [[0, -1, 0], [0, 0, -1], [1, 0, 0]],
-            Rotation::Bank180 => m = [[1, 0, 0], [0, -1, 0], [0, 0, -1]],
-            Rotation::Bank180Heading90 => m = [[0, 0, -1], [0, -1, 0], [-1, 0, 0]],
-            Rotation::Bank180Heading180 => m = [[-1, 0, 0], [0, -1, 0], [0, 0, 1]],
-            Rotation::Bank180Heading270 => m = [[0, 0, 1], [0, -1, 0], [1, 0, 0]],
-            Rotation::Bank270 => m = [[1, 0, 0], [0, 0, 1], [0, -1, 0]],
-            Rotation::Bank270Heading90 => m = [[0, -1, 0], [0, 0, 1], [-1, 0, 0]],
-            Rotation::Bank270Heading180 => m = [[-1, 0, 0], [0, 0, 1], [0, 1, 0]],
-            Rotation::Bank270Heading270 => m = [[0, 1, 0], [0, 0, 1], [1, 0, 0]],
+    fn identity() -> Self {
+        RotationMatrix([[1, 0, 0], [0, 1, 0], [0, 0, 1]])
+    }
+
+    fn compose(self, rhs: Self) -> Self {
+        let mut m = [[0; 3]; 3];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..3).map(|k| self.0[i][k] * rhs.0[k][j]).sum();
+            }
         }
         RotationMatrix(m)
     }
+
+    // Every signed permutation matrix is orthogonal, so its inverse is its transpose.
+    fn inverse(self) -> Self {
+        let mut m = [[0; 3]; 3];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = self.0[j][i];
+            }
+        }
+        RotationMatrix(m)
+    }
+
+    fn determinant(self) -> i32 {
+        let m = self.0;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    // All 24 proper rotations that map axes onto axes, i.e. the signed
+    // permutation matrices with determinant +1. With reflections, all 48
+    // signed permutation matrices are returned instead.
+    fn group(reflections: bool) -> Vec<Self> {
+        let mut group = Vec::with_capacity(48);
+        for p in [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ] {
+            for signs in 0..8 {
+                let mut m = [[0; 3]; 3];
+                for (i, row) in m.iter_mut().enumerate() {
+                    row[p[i]] = if signs & (1 << i) == 0 { 1 } else { -1 };
+                }
+                let m = RotationMatrix(m);
+                if reflections || m.determinant() == 1 {
+                    group.push(m);
+                }
+            }
+        }
+        debug_assert!(Self::is_closed(&group));
+        group
+    }
+
+    fn is_closed(group: &[Self]) -> bool {
+        let members: HashSet<Self> = group.iter().copied().collect();
+        members.contains(&Self::identity())
+            && group.iter().all(|a| {
+                members.contains(&a.inverse())
+                    && group.iter().all(|&b| members.contains(&a.compose(b)))
+            })
+    }
 }
 
 #[derive(Copy, Clone, Default, Eq, Hash, PartialEq)]
@@ -131,6 +141,11 @@ impl Position {
         let d = self - rhs;
         d.x.abs() + d.y.abs() + d.z.abs()
     }
+
+    fn distance_sq(self, rhs: Self) -> i64 {
+        let d = self - rhs;
+        (d.x as i64).pow(2) + (d.y as i64).pow(2) + (d.z as i64).pow(2)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
@@ -156,9 +171,35 @@ impl FromStr for Beacon {
     }
 }
 
+const DEFAULT_MIN_OVERLAP: usize = 12;
+
+// Sorted squared distances between every pair of beacons seen by a scanner.
+// They don't depend on the scanner's position or orientation, so they can be
+// compared before trying any rotations.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Fingerprint(Vec<i64>);
+
+impl Fingerprint {
+    fn common(&self, other: &Self) -> usize {
+        let (mut i, mut j, mut n) = (0, 0, 0);
+        while i < self.0.len() && j < other.0.len() {
+            match self.0[i].cmp(&other.0[j]) {
+                Ordering::Less => i += 1,
+                Ordering::Greater => j += 1,
+                Ordering::Equal => {
+                    n += 1;
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        n
+    }
+}
+
 #[derive(Clone, Debug)]
 struct OverlapInfo {
-    other_scanner_rot: Rotation,
+    other_scanner_rot: RotationMatrix,
     other_scanner_pos: Position,
     beacon_idx_mappings: HashMap<usize, usize>,
 }
@@ -166,84 +207,110 @@ struct OverlapInfo {
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct Scanner {
     pos: Position,
+    rot: RotationMatrix,
     beacons: Vec<Beacon>,
 }
 
 impl Scanner {
-    fn rotate(&mut self, r: Rotation) {
-        let m = RotationMatrix::from_rot(r);
+    fn rotate(&mut self, m: RotationMatrix) {
+        self.rot = m.compose(self.rot);
         for b in self.beacons.iter_mut() {
             b.pos = b.pos.rotate(m);
         }
     }
 
+    fn fingerprint(&self) -> Fingerprint {
+        let mut distances = Vec::with_capacity(self.beacons.len() * self.beacons.len() / 2);
+        for (i, a) in self.beacons.iter().enumerate() {
+            for b in self.beacons[i + 1..].iter() {
+                distances.push(a.pos.distance_sq(b.pos));
+            }
+        }
+        distances.sort_unstable();
+        Fingerprint(distances)
+    }
+
     fn set_pos(&mut self, p: Position) {
         self.pos = p;
         for b in self.beacons.iter_mut() {
-            b.pos -= p;
+            b.pos += p;
         }
     }
 
-    fn get_overlapping_beacons(&self, other: &Self) -> Option<OverlapInfo> {
-        for r in [
-            Rotation::None,
-            Rotation::Heading90,
-            Rotation::Heading180,
-            Rotation::Heading270,
-            Rotation::Altitude90,
-            Rotation::Altitude90Heading90,
-            Rotation::Altitude90Heading180,
-            Rotation::Altitude90Heading270,
-            Rotation::Altitude270,
-            Rotation::Altitude270Heading90,
-            Rotation::Altitude270Heading180,
-            Rotation::Altitude270Heading270,
-            Rotation::Bank90,
-            Rotation::Bank90Heading90,
-            Rotation::Bank90Heading180,
-            Rotation::Bank90Heading270,
-            Rotation::Bank180,
-            Rotation::Bank180Heading90,
-            Rotation::Bank180Heading180,
-            Rotation::Bank180Heading270,
-            Rotation::Bank270,
-            Rotation::Bank270Heading90,
-            Rotation::Bank270Heading180,
-            Rotation::Bank270Heading270,
-        ] {
-            let m = RotationMatrix::from_rot(r);
+    fn get_overlapping_beacons(
+        &self,
+        other: &Self,
+        rotations: &[RotationMatrix],
+        min_overlap: usize,
+    ) -> Option<OverlapInfo> {
+        for &m in rotations {
             let mut differences = HashMap::new();
             for (i, a) in self.beacons.iter().enumerate() {
                 for (j, b) in other.beacons.iter().enumerate() {
-                    let difference = b.pos.rotate(m) - a.pos;
+                    let difference = a.pos - b.pos.rotate(m);
                     differences
                         .entry(difference)
                         .or_insert(HashSet::new())
                         .insert((i, j));
                 }
             }
-            match differences.iter().filter(|(_, v)| v.len() >= 12).next() {
-                Some((p, v)) => {
-                    let mut info = OverlapInfo {
-                        other_scanner_rot: r,
-                        other_scanner_pos: *p,
-                        beacon_idx_mappings: HashMap::new(),
-                    };
-                    for &(k, v) in v.iter() {
-                        info.beacon_idx_mappings.insert(k, v);
-                    }
-                    return Option::from(info);
+            if let Some((p, v)) = differences.iter().find(|(_, v)| v.len() >= min_overlap) {
+                let mut info = OverlapInfo {
+                    other_scanner_rot: m,
+                    other_scanner_pos: *p,
+                    beacon_idx_mappings: HashMap::new(),
+                };
+                for &(k, v) in v.iter() {
+                    info.beacon_idx_mappings.insert(k, v);
                 }
-                None => (),
+                return Option::from(info);
             }
         }
         None
     }
 }
 
+// How a scanner was aligned against one that was already in place, with the
+// pairs of (parent beacon, scanner beacon) indices that were matched up.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Alignment {
+    scanner: usize,
+    parent: usize,
+    beacon_pairs: Vec<(usize, usize)>,
+}
+
+// Scanners that could be aligned with each other, along with which scanner
+// each one was aligned against.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct ScannerCluster {
+    scanners: Vec<usize>,
+    links: Vec<(usize, usize)>,
+}
+
+impl fmt::Display for ScannerCluster {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut scanners = self.scanners.clone();
+        scanners.sort_unstable();
+        let scanners = scanners.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+        write!(f, "[{}]", scanners.join(", "))?;
+        if !self.links.is_empty() {
+            let links = self
+                .links
+                .iter()
+                .map(|(i, j)| format!("{} via {}", i, j))
+                .collect::<Vec<_>>();
+            write!(f, " ({})", links.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct Region {
     scanners: VecDeque<Scanner>,
+    rotations: Vec<RotationMatrix>,
+    min_overlap: usize,
+    alignments: Vec<Alignment>,
 }
 
 impl FromStr for Region {
@@ -272,41 +339,168 @@ impl FromStr for Region {
                 }
                 scanners.push_back(Scanner {
                     pos: Default::default(),
-                    beacons: beacons,
+                    rot: RotationMatrix::identity(),
+                    beacons,
                 });
             }
         }
-        Ok(Region { scanners: scanners })
+        Ok(Region {
+            scanners,
+            rotations: RotationMatrix::group(false),
+            min_overlap: DEFAULT_MIN_OVERLAP,
+            alignments: Vec::new(),
+        })
     }
 }
 
 impl Region {
-    fn map_scanners(&mut self) {
-        if self.scanners.len() <= 1 {
-            return;
+    fn with_min_overlap(mut self, min_overlap: usize) -> Result<Self, Box<dyn Error>> {
+        if min_overlap == 0 {
+            return Err("minimum overlap must be at least 1 beacon".into());
         }
-        let mut todo: HashSet<usize> = (1..self.scanners.len()).collect();
-        let mut done: HashSet<usize> = [0].into_iter().collect();
-        let mut last_len = todo.len();
-        while todo.len() > 0 {
-            'outer: for &i in done.iter() {
-                for &j in todo.iter() {
-                    let overlap = self.scanners[i].get_overlapping_beacons(&self.scanners[j]);
-                    if overlap.is_some() {
-                        todo.remove(&j);
-                        done.insert(j);
-                        let o = overlap.unwrap();
+        self.min_overlap = min_overlap;
+        Ok(self)
+    }
+
+    // Also tries mirror images of each scanner when aligning them.
+    fn with_reflections(mut self, reflections: bool) -> Self {
+        self.rotations = RotationMatrix::group(reflections);
+        self
+    }
+
+    // Aligns every scanner to the frame of scanner 0. If some scanners can't be
+    // reached, the remaining ones are grouped into clusters of their own so that
+    // the error can report which scanners were connected to which.
+    fn map_scanners(&mut self) -> Result<(), Box<dyn Error>> {
+        // Two scanners sharing `min_overlap` beacons share every distance between them.
+        let min_common_distances = self.min_overlap * self.min_overlap.saturating_sub(1) / 2;
+        let fingerprints: Vec<Fingerprint> =
+            self.scanners.iter().map(Scanner::fingerprint).collect();
+        let mut aligned = vec![false; self.scanners.len()];
+        let mut clusters: Vec<ScannerCluster> = Vec::new();
+        self.alignments.clear();
+        while let Some(root) = aligned.iter().position(|&a| !a) {
+            aligned[root] = true;
+            let mut cluster = ScannerCluster {
+                scanners: vec![root],
+                links: Vec::new(),
+            };
+            let mut queue: VecDeque<usize> = [root].into_iter().collect();
+            while let Some(i) = queue.pop_front() {
+                // Only run the full rotation check on scanners whose fingerprints
+                // could overlap, trying the most promising ones first.
+                let mut candidates: Vec<(usize, usize)> = (0..self.scanners.len())
+                    .filter(|&j| !aligned[j])
+                    .map(|j| (fingerprints[i].common(&fingerprints[j]), j))
+                    .filter(|&(common, _)| common >= min_common_distances)
+                    .collect();
+                candidates.sort_unstable_by(|a, b| b.cmp(a));
+                for (_, j) in candidates {
+                    if let Some(o) = self.scanners[i].get_overlapping_beacons(
+                        &self.scanners[j],
+                        &self.rotations,
+                        self.min_overlap,
+                    ) {
+                        aligned[j] = true;
                         self.scanners[j].rotate(o.other_scanner_rot);
                         self.scanners[j].set_pos(o.other_scanner_pos);
-                        break 'outer;
+                        cluster.scanners.push(j);
+                        cluster.links.push((j, i));
+                        let mut beacon_pairs: Vec<(usize, usize)> =
+                            o.beacon_idx_mappings.into_iter().collect();
+                        beacon_pairs.sort_unstable();
+                        self.alignments.push(Alignment {
+                            scanner: j,
+                            parent: i,
+                            beacon_pairs,
+                        });
+                        queue.push_back(j);
                     }
                 }
             }
-            if todo.len() == last_len {
-                panic!("no beacon could be mapped")
-            }
-            last_len = todo.len();
+            clusters.push(cluster);
+        }
+        if clusters.len() > 1 {
+            let clusters = clusters.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+            return Err(format!(
+                "scanners split into {} disconnected clusters: {}",
+                clusters.len(),
+                clusters.join("; ")
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn report_text(&self) -> String {
+        let mut report = String::new();
+        for (i, s) in self.scanners.iter().enumerate() {
+            report += &format!(
+                "scanner {}: position {:?}, orientation {:?}\n",
+                i, s.pos, s.rot.0
+            );
+        }
+        for a in self.alignments.iter() {
+            let pairs = a
+                .beacon_pairs
+                .iter()
+                .map(|(i, j)| format!("{}-{}", i, j))
+                .collect::<Vec<_>>();
+            report += &format!(
+                "scanner {} aligned against scanner {} on {} beacons: {}\n",
+                a.scanner,
+                a.parent,
+                pairs.len(),
+                pairs.join(", ")
+            );
         }
+        report
+    }
+
+    fn report_json(&self) -> String {
+        let scanners = self
+            .scanners
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let orientation = s
+                    .rot
+                    .0
+                    .iter()
+                    .map(|r| format!("[{},{},{}]", r[0], r[1], r[2]))
+                    .collect::<Vec<_>>();
+                format!(
+                    "{{\"id\":{},\"position\":[{},{},{}],\"orientation\":[{}]}}",
+                    i,
+                    s.pos.x,
+                    s.pos.y,
+                    s.pos.z,
+                    orientation.join(",")
+                )
+            })
+            .collect::<Vec<_>>();
+        let alignments = self
+            .alignments
+            .iter()
+            .map(|a| {
+                let pairs = a
+                    .beacon_pairs
+                    .iter()
+                    .map(|(i, j)| format!("[{},{}]", i, j))
+                    .collect::<Vec<_>>();
+                format!(
+                    "{{\"scanner\":{},\"parent\":{},\"beacon_pairs\":[{}]}}",
+                    a.scanner,
+                    a.parent,
+                    pairs.join(",")
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"scanners\":[{}],\"alignments\":[{}]}}",
+            scanners.join(","),
+            alignments.join(",")
+        )
     }
 
     fn get_all_beacons(&self) -> HashSet<Position> {
@@ -334,14 +528,105 @@ impl Region {
     }
 }
 
+fn read_region(
+    input: &str,
+    min_overlap: usize,
+    reflections: bool,
+) -> Result<Region, Box<dyn Error>> {
+    let region: Region = std::fs::read_to_string(input)?.parse()?;
+    let mut region = region
+        .with_min_overlap(min_overlap)?
+        .with_reflections(reflections);
+    region.map_scanners()?;
+    Ok(region)
+}
+
+fn solve(
+    input: &str,
+    problem: i32,
+    min_overlap: usize,
+    reflections: bool,
+) -> Result<String, Box<dyn Error>> {
+    let region = read_region(input, min_overlap, reflections)?;
+    match problem {
+        1 => Ok(region.get_all_beacons().len().to_string()),
+        _ => Ok(region.get_largest_manhattan_distance().to_string()),
+    }
+}
+
 pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
-    let mut region: Region = std::fs::read_to_string(input)?.parse()?;
-    region.map_scanners();
-    Ok(region.get_all_beacons().len().to_string())
+    solve(input, 1, DEFAULT_MIN_OVERLAP, false)
 }
 
 pub fn prob_2(input: &str) -> Result<String, Box<dyn Error>> {
-    let mut region: Region = std::fs::read_to_string(input)?.parse()?;
-    region.map_scanners();
-    Ok(region.get_largest_manhattan_distance().to_string())
+    solve(input, 2, DEFAULT_MIN_OVERLAP, false)
+}
+
+fn report(
+    input: &str,
+    format: &str,
+    min_overlap: usize,
+    reflections: bool,
+) -> Result<String, Box<dyn Error>> {
+    let region = read_region(input, min_overlap, reflections)?;
+    match format {
+        "text" => Ok(region.report_text()),
+        "json" => Ok(region.report_json()),
+        _ => Err(format!("{} is an invalid report format", format).into()),
+    }
+}
+
+// Solves either problem, or prints the alignment report with `--report FORMAT`,
+// taking `--min-overlap N` to change how many beacons scanners must share and
+// `--reflections` to also align mirrored scanners.
+pub fn run_with_options(
+    input: &str,
+    problem: i32,
+    opts: &[&str],
+) -> Result<String, Box<dyn Error>> {
+    let mut format = None;
+    let mut min_overlap = DEFAULT_MIN_OVERLAP;
+    let mut reflections = false;
+    let mut opts = opts.iter();
+    while let Some(&opt) = opts.next() {
+        if opt == "--reflections" {
+            reflections = true;
+            continue;
+        }
+        match (opt, opts.next()) {
+            ("--report", Some(&f)) => format = Some(f),
+            ("--min-overlap", Some(n)) => min_overlap = n.parse()?,
+            ("--report" | "--min-overlap", None) => {
+                return Err(format!("{} needs a value", opt).into())
+            }
+            _ => return Err(format!("{} is an invalid option", opt).into()),
+        }
+    }
+    match format {
+        Some(format) => report(input, format, min_overlap, reflections),
+        None => Ok(format!(
+            "{}\n",
+            solve(input, problem, min_overlap, reflections)?
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_group_is_closed() {
+        let rotations = RotationMatrix::group(false);
+        assert_eq!(rotations.len(), 24);
+        assert!(rotations.iter().all(|m| m.determinant() == 1));
+        assert!(RotationMatrix::is_closed(&rotations));
+    }
+
+    #[test]
+    fn group_with_reflections_is_closed() {
+        let transforms = RotationMatrix::group(true);
+        assert_eq!(transforms.len(), 48);
+        assert!(RotationMatrix::is_closed(&transforms));
+    }
 }
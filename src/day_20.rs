@@ -1,4 +1,3 @@
-use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt::{self, Debug};
 use std::str::FromStr;
@@ -8,57 +7,87 @@ struct ImageEnhancementAlgorithm {
     alg: Vec<bool>,
 }
 
+fn parse_pixel(c: char) -> Option<bool> {
+    match c {
+        '#' => Some(true),
+        '.' => Some(false),
+        _ => None,
+    }
+}
+
 impl FromStr for ImageEnhancementAlgorithm {
     type Err = Box<dyn Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(ImageEnhancementAlgorithm {
-            alg: s
-                .chars()
-                .map(|c| match c {
-                    '#' => true,
-                    _ => false,
-                })
-                .collect(),
-        })
+        let alg = s
+            .chars()
+            .enumerate()
+            .map(|(i, c)| parse_pixel(c).ok_or(format!("invalid pixel '{}' at index {}", c, i)))
+            .collect::<Result<Vec<_>, _>>()?;
+        // One output pixel for every possible 3x3 window.
+        if alg.len() != 512 {
+            return Err(format!("algorithm has {} entries instead of 512", alg.len()).into());
+        }
+        Ok(ImageEnhancementAlgorithm { alg })
     }
 }
 
 impl ImageEnhancementAlgorithm {
-    fn get(&self, p: [bool; 9]) -> bool {
-        let mut idx = 0;
-        for (i, &v) in p.iter().rev().enumerate() {
-            if v {
-                idx |= 1 << i;
-            }
-        }
+    fn get_index(&self, idx: usize) -> bool {
         self.alg[idx]
     }
 }
 
+// A lit background means the whole infinite grid outside the image is lit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum LitPixels {
+    Finite(usize),
+    Infinite,
+}
+
+impl fmt::Display for LitPixels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LitPixels::Finite(n) => write!(f, "{}", n),
+            LitPixels::Infinite => write!(f, "infinite"),
+        }
+    }
+}
+
+// An image on an infinite grid: a rectangle of pixels somewhere on it, with
+// every pixel outside the rectangle set to `background_px`.
 #[derive(Clone, Eq, PartialEq)]
 struct Image {
     background_px: bool,
-    buf: Vec<Vec<bool>>,
+    // Grid position of the top-left pixel of the rectangle.
+    origin: (i64, i64),
+    width: usize,
+    height: usize,
+    // Each row packed 64 pixels to a word, starting from the least significant bit.
+    rows: Vec<Vec<u64>>,
 }
 
 impl FromStr for Image {
     type Err = Box<dyn Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut i = Image {
-            background_px: false,
-            buf: Vec::new(),
-        };
-        for l in s.lines() {
-            i.buf.push(
-                l.chars()
-                    .map(|c| match c {
-                        '#' => true,
-                        _ => false,
-                    })
-                    .collect(),
-            );
+        let lines = s.lines().collect::<Vec<_>>();
+        let width = lines.first().map_or(0, |l| l.chars().count());
+        let mut i = Image::new(width, lines.len(), false);
+        for (y, l) in lines.iter().enumerate() {
+            if l.chars().count() != width {
+                return Err(format!("row {} is not {} pixels wide", y + 1, width).into());
+            }
+            for (x, c) in l.chars().enumerate() {
+                match parse_pixel(c) {
+                    Some(v) => i.set(x, y, v),
+                    None => {
+                        let (row, col) = (y + 1, x + 1);
+                        let msg = format!("invalid pixel '{}' at row {}, column {}", c, row, col);
+                        return Err(msg.into());
+                    }
+                }
+            }
         }
         Ok(i)
     }
@@ -67,156 +96,410 @@ impl FromStr for Image {
 impl Debug for Image {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let bkgd_char = if self.background_px { '#' } else { '.' }.to_string();
-        let len = self.buf.len();
         for _ in 0..3 {
-            writeln!(f, "{}", bkgd_char.repeat(len + 6))?;
+            writeln!(f, "{}", bkgd_char.repeat(self.width + 6))?;
         }
-        for l in self.buf.iter() {
+        for y in 0..self.height {
             write!(f, "{}", bkgd_char.repeat(3))?;
             write!(
                 f,
                 "{}",
-                l.iter()
-                    .map(|&b| if b { '#' } else { '.' })
+                (0..self.width)
+                    .map(|x| if self.get(x as i64, y as i64) {
+                        '#'
+                    } else {
+                        '.'
+                    })
                     .collect::<String>()
             )?;
             writeln!(f, "{}", bkgd_char.repeat(3))?;
         }
         for _ in 0..2 {
-            writeln!(f, "{}", bkgd_char.repeat(len + 6))?;
+            writeln!(f, "{}", bkgd_char.repeat(self.width + 6))?;
         }
-        write!(f, "{}", bkgd_char.repeat(len + 6))
+        write!(f, "{}", bkgd_char.repeat(self.width + 6))
     }
 }
 
 impl Image {
-    fn get_lit_pixels(&self) -> usize {
-        self.buf
-            .iter()
-            .map(|l| l.iter().filter(|&v| *v).count())
-            .sum()
+    fn new(width: usize, height: usize, background_px: bool) -> Self {
+        Image {
+            background_px,
+            origin: (0, 0),
+            width,
+            height,
+            rows: vec![vec![0; width.div_ceil(64)]; height],
+        }
     }
 
+    // Gets a pixel relative to the top-left of the rectangle, which may lie
+    // outside of it.
+    fn get(&self, x: i64, y: i64) -> bool {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return self.background_px;
+        }
+        let (x, y) = (x as usize, y as usize);
+        (self.rows[y][x / 64] >> (x % 64)) & 1 == 1
+    }
+
+    fn set(&mut self, x: usize, y: usize, v: bool) {
+        if v {
+            self.rows[y][x / 64] |= 1 << (x % 64);
+        } else {
+            self.rows[y][x / 64] &= !(1 << (x % 64));
+        }
+    }
+
+    fn get_lit_pixels(&self) -> LitPixels {
+        if self.background_px {
+            return LitPixels::Infinite;
+        }
+        LitPixels::Finite(
+            self.rows
+                .iter()
+                .flatten()
+                .map(|w| w.count_ones() as usize)
+                .sum(),
+        )
+    }
+
+    // Marks which bits of the `w`th word of a row hold pixels of the rectangle.
+    fn word_mask(&self, w: usize) -> u64 {
+        match self.width.saturating_sub(64 * w) {
+            n if n >= 64 => u64::MAX,
+            n => (1 << n) - 1,
+        }
+    }
+
+    // The `w`th word of pixels of a row that's entirely background.
+    fn background_word(&self, w: usize) -> u64 {
+        if self.background_px {
+            self.word_mask(w)
+        } else {
+            0
+        }
+    }
+
+    // Shrinks the rectangle to the smallest one holding every pixel that
+    // differs from the background.
     fn trim(&mut self) {
-        let old_len = self.buf.len();
-
-        let mut rows_to_remove = VecDeque::new();
-        for (i, l) in self.buf.iter().enumerate() {
-            if l.iter()
-                .map(|&b| if b != self.background_px { 1 } else { 0 })
-                .sum::<u8>()
-                == 0
-            {
-                rows_to_remove.push_back(i);
+        let words = self.width.div_ceil(64);
+        let differs = |row: &Vec<u64>| (0..words).any(|w| row[w] != self.background_word(w));
+        let (top, bottom) = match (
+            self.rows.iter().position(differs),
+            self.rows.iter().rposition(differs),
+        ) {
+            (Some(top), Some(bottom)) => (top, bottom),
+            _ => {
+                *self = Image {
+                    origin: self.origin,
+                    ..Image::new(0, 0, self.background_px)
+                };
+                return;
+            }
+        };
+        // Columns holding any pixel that differs from the background.
+        let mut cols = vec![0; words];
+        for row in self.rows[top..=bottom].iter() {
+            for (w, c) in cols.iter_mut().enumerate() {
+                *c |= row[w] ^ self.background_word(w);
             }
         }
-        let mut cols_to_remove = VecDeque::new();
-        for j in 0..old_len {
-            let mut remove = true;
-            for i in 0..self.buf.len() {
-                if self.buf[i][j] != self.background_px {
-                    remove = false;
-                    break;
+        let left = cols.iter().position(|&c| c != 0).unwrap();
+        let left = 64 * left + cols[left].trailing_zeros() as usize;
+        let right = cols.iter().rposition(|&c| c != 0).unwrap();
+        let right = 64 * right + 63 - cols[right].leading_zeros() as usize;
+        if (top, left, bottom, right) == (0, 0, self.height - 1, self.width - 1) {
+            return;
+        }
+        let mut trimmed = Image::new(right - left + 1, bottom - top + 1, self.background_px);
+        trimmed.origin = (self.origin.0 + left as i64, self.origin.1 + top as i64);
+        let last_mask = trimmed.word_mask(trimmed.width.div_ceil(64) - 1);
+        for (new, old) in trimmed.rows.iter_mut().zip(self.rows[top..=bottom].iter()) {
+            for (w, word) in new.iter_mut().enumerate() {
+                let start = left + 64 * w;
+                let (i, shift) = (start / 64, start % 64);
+                *word = old[i] >> shift;
+                if shift != 0 && i + 1 < old.len() {
+                    *word |= old[i + 1] << (64 - shift);
                 }
             }
-            if remove {
-                cols_to_remove.push_back(j);
+            if let Some(word) = new.last_mut() {
+                *word &= last_mask;
             }
         }
+        *self = trimmed;
+    }
 
-        // Reduce the cols to remove list to match rows to remove to keep the image a square, if
-        // necessary.
-        let mut alt = 0;
-        while cols_to_remove.len() > rows_to_remove.len() {
-            if alt % 2 == 0 {
-                cols_to_remove.pop_front();
-            } else {
-                cols_to_remove.pop_back();
+    fn enhance(&mut self, alg: &ImageEnhancementAlgorithm) {
+        // Every pixel within one of the rectangle can change, so it grows by
+        // one on each side.
+        let mut new_img = Image::new(
+            self.width + 2,
+            self.height + 2,
+            alg.get_index(if self.background_px { 511 } else { 0 }),
+        );
+        new_img.origin = (self.origin.0 - 1, self.origin.1 - 1);
+        let bkgd_bits = if self.background_px { 0b111_111_111 } else { 0 };
+        let bkgd_word = if self.background_px { u64::MAX } else { 0 };
+        // The `w`th word of pixels of old row `y - 2`, including the background
+        // past its end.
+        let word = |y: usize, w: usize| -> u64 {
+            if y < 2 || y - 2 >= self.height {
+                return bkgd_word;
             }
-            alt += 1;
-        }
-        // Reduce the rows to remove list to match cols to remove to keep the image a square, if
-        // necessary.
-        let mut alt = 0;
-        while rows_to_remove.len() > cols_to_remove.len() {
-            if alt % 2 == 0 {
-                rows_to_remove.pop_front();
-            } else {
-                rows_to_remove.pop_back();
+            let row = &self.rows[y - 2];
+            row.get(w)
+                .map_or(bkgd_word, |&v| v | (bkgd_word & !self.background_word(w)))
+        };
+        let last_mask = new_img.word_mask(new_img.width.div_ceil(64) - 1);
+        for (y, new_row) in new_img.rows.iter_mut().enumerate() {
+            // The new row is centred on old row y - 1, so it needs old rows y - 2
+            // to y.
+            // Slide a 3x3 window along the row, shifting out the column on the
+            // left and bringing in a new one on the right.
+            let mut idx = bkgd_bits;
+            for (w, new_word) in new_row.iter_mut().enumerate() {
+                let (top, mid, bot) = (word(y, w), word(y + 1, w), word(y + 2, w));
+                for b in 0..64 {
+                    idx = ((idx << 1) & 0b110_110_110)
+                        | ((((top >> b) & 1) as usize) << 6)
+                        | ((((mid >> b) & 1) as usize) << 3)
+                        | (((bot >> b) & 1) as usize);
+                    *new_word |= (alg.get_index(idx) as u64) << b;
+                }
+            }
+            // Clear anything computed past the end of the row.
+            if let Some(last) = new_row.last_mut() {
+                *last &= last_mask;
             }
-            alt += 1;
         }
+        *self = new_img;
+        self.trim();
+    }
+}
 
-        // Remove rows.
-        let mut num_removed = 0;
-        for &i in rows_to_remove.iter() {
-            self.buf.remove(i - num_removed);
-            num_removed += 1;
+impl Image {
+    // Renders the part of the grid starting at `origin` as a plain PBM image,
+    // with lit pixels in black.
+    fn to_pbm(&self, origin: (i64, i64), width: usize, height: usize) -> String {
+        let mut pbm = format!("P1\n{} {}\n", width, height);
+        let (dx, dy) = (origin.0 - self.origin.0, origin.1 - self.origin.1);
+        for y in 0..height as i64 {
+            let row = (0..width as i64)
+                .map(|x| if self.get(x + dx, y + dy) { '1' } else { '0' })
+                .collect::<Vec<_>>();
+            // PBM lines shouldn't be longer than 70 characters.
+            for chunk in row.chunks(70) {
+                pbm.extend(chunk);
+                pbm.push('\n');
+            }
         }
+        pbm
+    }
+
+    // Compares everything that decides how the images evolve, leaving out
+    // where they are on the grid since that doesn't affect the pixels they
+    // turn into.
+    fn same_shape(&self, other: &Image) -> bool {
+        self.background_px == other.background_px
+            && self.width == other.width
+            && self.height == other.height
+            && self.rows == other.rows
+    }
+}
+
+// Most images never repeat, so this is as far as `EnhancementRun` looks for a
+// cycle before giving up on larger step counts.
+const MAX_SIMULATED_STEPS: usize = 1000;
 
-        // Remove cols.
-        let mut num_removed = 0;
-        for &j in cols_to_remove.iter() {
-            for i in 0..self.buf.len() {
-                self.buf[i].remove(j - num_removed);
+// The lit pixel counts of an image as it's enhanced, up to the point where it
+// repeats an earlier shape or its background stays lit for good. From there on
+// the counts repeat too.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct EnhancementRun {
+    lit_pixels: Vec<LitPixels>,
+    // The step at which the cycle starts and its length.
+    cycle: Option<(usize, usize)>,
+    // The step from which the background is lit for good.
+    lit_from: Option<usize>,
+    // Whether the dark background lights up on every odd step and goes dark
+    // again on every even one.
+    blinks: bool,
+}
+
+impl EnhancementRun {
+    // Finds cycles with Brent's algorithm, so only one earlier image is kept
+    // around however many steps are taken.
+    fn new(
+        img: Image,
+        alg: &ImageEnhancementAlgorithm,
+        steps: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        let stays_lit = alg.get_index(511);
+        let mut run = EnhancementRun {
+            blinks: !img.background_px && alg.get_index(0) && !stays_lit,
+            ..Default::default()
+        };
+        let mut hare = img.clone();
+        run.lit_pixels.push(hare.get_lit_pixels());
+        // The background is lit after any odd number of steps, so there's no
+        // need to look at the image.
+        if run.blinks && steps % 2 == 1 {
+            return Ok(run);
+        }
+        let mut tortoise = img.clone();
+        let (mut power, mut len) = (1, 0);
+        for step in 1..=(steps as usize).min(MAX_SIMULATED_STEPS) {
+            if hare.background_px && stays_lit {
+                run.lit_from = Some(step - 1);
+                return Ok(run);
+            }
+            hare.enhance(alg);
+            len += 1;
+            if hare.same_shape(&tortoise) {
+                run.cycle = Some((Self::cycle_start(img, alg, len), len));
+                return Ok(run);
+            }
+            run.lit_pixels.push(hare.get_lit_pixels());
+            if len == power {
+                tortoise = hare.clone();
+                power *= 2;
+                len = 0;
             }
-            num_removed += 1;
         }
+        if steps as usize > MAX_SIMULATED_STEPS {
+            let msg = format!("no cycle found within {} steps", MAX_SIMULATED_STEPS);
+            return Err(msg.into());
+        }
+        Ok(run)
     }
 
-    fn enhance(&mut self, alg: &ImageEnhancementAlgorithm) {
-        let old_len = self.buf.len();
-        let new_len = old_len + 6;
-        let new_background_px = alg.get([self.background_px; 9]);
-        let mut new_img = Vec::with_capacity(new_len);
-        for _ in 0..new_len {
-            new_img.push(vec![false; new_len]);
-        }
-
-        for x in 0..new_len {
-            for y in 0..new_len {
-                let mut v = [self.background_px; 9];
-                let mut k = 0;
-                for i in -1..=1 {
-                    for j in -1..=1 {
-                        let ix = x as i32 + i - 3;
-                        let jy = y as i32 + j - 3;
-                        if ix >= 0 && jy >= 0 && (ix as usize) < old_len && (jy as usize) < old_len
-                        {
-                            v[k] = self.buf[ix as usize][jy as usize];
-                        }
-                        k += 1;
-                    }
-                }
-                new_img[x][y] = alg.get(v);
-            }
+    // The first step that is repeated `len` steps later.
+    fn cycle_start(img: Image, alg: &ImageEnhancementAlgorithm, len: usize) -> usize {
+        let mut ahead = img.clone();
+        for _ in 0..len {
+            ahead.enhance(alg);
+        }
+        let mut behind = img;
+        let mut start = 0;
+        while !behind.same_shape(&ahead) {
+            behind.enhance(alg);
+            ahead.enhance(alg);
+            start += 1;
         }
+        start
+    }
 
-        self.buf = new_img;
-        self.background_px = new_background_px;
-        self.trim();
+    fn lit_pixels_after(&self, steps: u64) -> LitPixels {
+        match (self.cycle, self.lit_from) {
+            _ if self.blinks && steps % 2 == 1 => LitPixels::Infinite,
+            (_, Some(from)) if steps as usize >= from => LitPixels::Infinite,
+            (Some((start, len)), _) if steps as usize >= start => {
+                self.lit_pixels[start + (steps as usize - start) % len]
+            }
+            _ => self.lit_pixels[steps as usize],
+        }
     }
 }
 
-pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
+fn read_input(input: &str) -> Result<(ImageEnhancementAlgorithm, Image), Box<dyn Error>> {
     let file = std::fs::read_to_string(input)?;
     let mut lines = file.lines();
-    let alg: ImageEnhancementAlgorithm = lines.next().unwrap().parse()?;
-    lines.next(); // Skip empty line.
-    let mut img: Image = lines.collect::<Vec<_>>().join("\n").parse()?;
+    let alg: ImageEnhancementAlgorithm = lines.next().ok_or("empty input")?.parse()?;
+    if lines.next().is_some_and(|l| !l.is_empty()) {
+        return Err("expected an empty line after the algorithm".into());
+    }
+    let img: Image = lines.collect::<Vec<_>>().join("\n").parse()?;
+    Ok((alg, img))
+}
+
+pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
+    let (alg, mut img) = read_input(input)?;
     img.enhance(&alg);
     img.enhance(&alg);
     Ok(img.get_lit_pixels().to_string())
 }
 
 pub fn prob_2(input: &str) -> Result<String, Box<dyn Error>> {
-    let file = std::fs::read_to_string(input)?;
-    let mut lines = file.lines();
-    let alg: ImageEnhancementAlgorithm = lines.next().unwrap().parse()?;
-    lines.next(); // Skip empty line.
-    let mut img: Image = lines.collect::<Vec<_>>().join("\n").parse()?;
+    let (alg, mut img) = read_input(input)?;
     for _ in 0..50 {
         img.enhance(&alg);
     }
     Ok(img.get_lit_pixels().to_string())
 }
+
+// Counts the lit pixels after any number of steps. This only finishes for
+// very large counts if the image settles into a cycle.
+pub fn lit_pixels_after(input: &str, steps: u64) -> Result<String, Box<dyn Error>> {
+    let (alg, img) = read_input(input)?;
+    let run = EnhancementRun::new(img, &alg, steps)?;
+    let mut out = format!("{}\n", run.lit_pixels_after(steps));
+    if let Some((start, len)) = run.cycle {
+        out += &format!("image repeats every {} steps from step {}\n", len, start);
+    }
+    if let Some(from) = run.lit_from {
+        out += &format!("background is lit for good from step {}\n", from);
+    }
+    if run.blinks {
+        out += "background is lit on every odd step\n";
+    }
+    Ok(out)
+}
+
+// Calls `f` with the image after every `every`th step, up to `steps`.
+fn for_each_frame(
+    mut img: Image,
+    alg: &ImageEnhancementAlgorithm,
+    steps: usize,
+    every: usize,
+    mut f: impl FnMut(usize, &Image) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    for step in 0..=steps {
+        if step > 0 {
+            img.enhance(alg);
+        }
+        if step % every == 0 {
+            f(step, &img)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes the image after every `every`th step, up to `steps`, as PBM files in
+// `dir`. All frames cover the same area so they line up.
+pub fn write_frames(
+    input: &str,
+    steps: usize,
+    dir: &str,
+    every: usize,
+) -> Result<String, Box<dyn Error>> {
+    if every == 0 {
+        return Err("the frame interval must be at least 1 step".into());
+    }
+    let (alg, img) = read_input(input)?;
+    // Trimming means the last frame might not be the largest, so a first pass
+    // grows the area to draw until it holds every frame. Frames are cheap to
+    // recompute but not to keep.
+    let (mut left, mut top, mut right, mut bottom) = (i64::MAX, i64::MAX, i64::MIN, i64::MIN);
+    for_each_frame(img.clone(), &alg, steps, every, |_, f| {
+        left = left.min(f.origin.0);
+        top = top.min(f.origin.1);
+        right = right.max(f.origin.0 + f.width as i64);
+        bottom = bottom.max(f.origin.1 + f.height as i64);
+        Ok(())
+    })?;
+    let (width, height) = ((right - left) as usize, (bottom - top) as usize);
+    std::fs::create_dir_all(dir)?;
+    let mut written = 0;
+    for_each_frame(img, &alg, steps, every, |step, f| {
+        let pbm = f.to_pbm((left, top), width, height);
+        std::fs::write(format!("{}/step_{:04}.pbm", dir, step), pbm)?;
+        written += 1;
+        Ok(())
+    })?;
+    Ok(format!(
+        "wrote {} {}x{} PBM frames to {}\n",
+        written, width, height, dir
+    ))
+}
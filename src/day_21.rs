@@ -1,146 +1,415 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::str::FromStr;
 
-#[derive(Clone, Debug)]
+#[derive(Copy, Clone, Debug)]
+struct Rules {
+    board_size: u64,
+    target: u64,
+    die_faces: u64,
+    rolls_per_turn: u64,
+}
+
+const DETERMINISTIC_RULES: Rules = Rules {
+    board_size: 10,
+    target: 1000,
+    die_faces: 100,
+    rolls_per_turn: 3,
+};
+
+// Largest total a quantum turn's rolls may add up to.
+const MAX_ROLL_SUM: u64 = 1 << 16;
+
+const QUANTUM_RULES: Rules = Rules {
+    board_size: 10,
+    target: 21,
+    die_faces: 3,
+    rolls_per_turn: 3,
+};
+
+impl Rules {
+    fn validate(&self, d: &DiracDice) -> Result<(), Box<dyn Error>> {
+        if self.board_size == 0
+            || self.target == 0
+            || self.die_faces == 0
+            || self.rolls_per_turn == 0
+        {
+            return Err(
+                "board size, target score, die faces and rolls per turn must be positive".into(),
+            );
+        }
+        for &pos in &d.positions {
+            if pos == 0 || pos > self.board_size {
+                return Err(format!(
+                    "starting position {} is not on a board of size {}",
+                    pos, self.board_size
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    // Each distinct sum of a turn's rolls along with the number of universes it occurs in.
+    fn roll_sums(&self) -> Result<Vec<(u64, u128)>, Box<dyn Error>> {
+        let max_sum = self.die_faces.checked_mul(self.rolls_per_turn);
+        if max_sum.is_none_or(|m| m > MAX_ROLL_SUM) {
+            return Err(format!(
+                "die faces times rolls per turn must be at most {} in the quantum game",
+                MAX_ROLL_SUM
+            )
+            .into());
+        }
+        let mut sums = vec![1u128];
+        for roll in 1..=self.rolls_per_turn {
+            let mut next = vec![0u128; sums.len() + self.die_faces as usize];
+            for (s, &n) in sums.iter().enumerate() {
+                for face in 1..=self.die_faces as usize {
+                    next[s + face] = (next[s + face].checked_add(n))
+                        .ok_or(format!("universe counts overflow on roll {}", roll))?;
+                }
+            }
+            sums = next;
+        }
+        Ok(sums
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, n)| n > 0)
+            .map(|(s, n)| (s as u64, n))
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 struct DiracDice {
-    p1_pos: u64,
-    p2_pos: u64,
-    p1_points: u64,
-    p2_points: u64,
+    positions: Vec<u64>,
+    points: Vec<u64>,
+    turn: usize,
 }
 
 impl FromStr for DiracDice {
     type Err = Box<dyn Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts = s.lines().collect::<Vec<_>>();
-        if parts.len() != 2 {
-            return Err("invalid input".into());
+        let mut positions = Vec::new();
+        for (i, l) in s.lines().enumerate() {
+            let (player, pos) = l
+                .strip_prefix("Player ")
+                .and_then(|l| l.split_once(" starting position: "))
+                .ok_or(format!("invalid player on line {}", i + 1))?;
+            if player.parse::<usize>()? != i + 1 {
+                return Err(format!("expected player {} on line {}", i + 1, i + 1).into());
+            }
+            positions.push(pos.parse()?);
+        }
+        if positions.is_empty() {
+            return Err("no players".into());
         }
         Ok(DiracDice {
-            p1_pos: parts[0].chars().last().unwrap().to_string().parse()?,
-            p2_pos: parts[1].chars().last().unwrap().to_string().parse()?,
-            p1_points: 0,
-            p2_points: 0,
+            points: vec![0; positions.len()],
+            positions,
+            turn: 0,
         })
     }
 }
 
 impl DiracDice {
-    fn roll_p1(&mut self, roll: u64) {
-        self.p1_pos += roll;
-        if self.p1_pos > 10 {
-            self.p1_pos = ((self.p1_pos - 1) % 10) + 1;
-        }
-        self.p1_points += self.p1_pos as u64;
-    }
-
-    fn roll_p2(&mut self, roll: u64) {
-        self.p2_pos += roll;
-        if self.p2_pos > 10 {
-            self.p2_pos = ((self.p2_pos - 1) % 10) + 1;
-        }
-        self.p2_points += self.p2_pos as u64;
+    // Moves the player whose turn it is and returns their index.
+    fn roll(&mut self, roll: u64, board_size: u64) -> usize {
+        let p = self.turn;
+        self.positions[p] = ((self.positions[p] + roll - 1) % board_size) + 1;
+        self.points[p] += self.positions[p];
+        self.turn = (p + 1) % self.positions.len();
+        p
     }
 }
 
 struct DeterministicDice {
+    faces: u64,
     next_roll: u64,
     num_rolls: u64,
 }
 
-impl Default for DeterministicDice {
-    fn default() -> Self {
+impl DeterministicDice {
+    fn new(faces: u64) -> Self {
         DeterministicDice {
+            faces,
             next_roll: 1,
             num_rolls: 0,
         }
     }
-}
 
-impl DeterministicDice {
     fn roll(&mut self) -> u64 {
         let r = self.next_roll;
         self.num_rolls += 1;
         self.next_roll += 1;
-        if self.next_roll > 100 {
+        if self.next_roll > self.faces {
             self.next_roll = 1;
         }
         r
     }
+
+    fn roll_turn(&mut self, rolls: u64) -> u64 {
+        (0..rolls).map(|_| self.roll()).sum()
+    }
 }
 
-fn roll_quantum_die(wins: &mut [u64; 2], d: &DiracDice, player: bool, num: u64) {
-    if player {
-        if d.p1_points >= 21 {
-            wins[0] += num;
-            return;
-        }
-    } else {
-        if d.p2_points >= 21 {
-            wins[1] += num;
-            return;
-        }
-    }
-
-    let mut d1 = d.clone();
-    let mut d2 = d.clone();
-    let mut d3 = d.clone();
-    let mut d4 = d.clone();
-    let mut d5 = d.clone();
-    let mut d6 = d.clone();
-    let mut d7 = d.clone();
-
-    if player {
-        d1.roll_p2(3);
-        d2.roll_p2(4);
-        d3.roll_p2(5);
-        d4.roll_p2(6);
-        d5.roll_p2(7);
-        d6.roll_p2(8);
-        d7.roll_p2(9);
-    } else {
-        d1.roll_p1(3);
-        d2.roll_p1(4);
-        d3.roll_p1(5);
-        d4.roll_p1(6);
-        d5.roll_p1(7);
-        d6.roll_p1(8);
-        d7.roll_p1(9);
+struct DeterministicOutcome {
+    points: Vec<u64>,
+    winner: usize,
+    num_rolls: u64,
+}
+
+impl DeterministicOutcome {
+    // The lowest losing score multiplied by the number of die rolls.
+    fn answer(&self) -> u128 {
+        let losing_points = (self.points.iter().enumerate())
+            .filter(|&(p, _)| p != self.winner)
+            .map(|(_, &points)| points)
+            .min()
+            .unwrap_or(0);
+        losing_points as u128 * self.num_rolls as u128
     }
+}
 
-    roll_quantum_die(wins, &d1, !player, 1 * num); // Sum 3 occurs 1 time.
-    roll_quantum_die(wins, &d2, !player, 3 * num); // Sum 3 occurs 3 times.
-    roll_quantum_die(wins, &d3, !player, 6 * num); // Sum 3 occurs 6 times.
-    roll_quantum_die(wins, &d4, !player, 7 * num); // Sum 3 occurs 7 times.
-    roll_quantum_die(wins, &d5, !player, 6 * num); // Sum 3 occurs 6 times.
-    roll_quantum_die(wins, &d6, !player, 3 * num); // Sum 3 occurs 3 times.
-    roll_quantum_die(wins, &d7, !player, 1 * num); // Sum 3 occurs 1 time.
+impl fmt::Display for DeterministicOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (p, points) in self.points.iter().enumerate() {
+            write!(f, "player {}: {} points", p + 1, points)?;
+            if p == self.winner {
+                write!(f, " (winner)")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "die rolls: {}", self.num_rolls)
+    }
 }
 
-pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
-    let mut d: DiracDice = std::fs::read_to_string(input)?.parse()?;
-    let mut die: DeterministicDice = Default::default();
-    let val;
+fn play_deterministic(mut d: DiracDice, rules: &Rules) -> DeterministicOutcome {
+    let mut die = DeterministicDice::new(rules.die_faces);
     loop {
-        d.roll_p1(die.roll() + die.roll() + die.roll());
-        if d.p1_points >= 1000 {
-            val = d.p2_points * die.num_rolls;
-            break;
+        let p = d.roll(die.roll_turn(rules.rolls_per_turn), rules.board_size);
+        if d.points[p] >= rules.target {
+            return DeterministicOutcome {
+                points: d.points,
+                winner: p,
+                num_rolls: die.num_rolls,
+            };
+        }
+    }
+}
+
+// Universe counts for a single player's turns: the number of universes in which the player is
+// still short of the target after each turn, and the number in which they reach it on that turn.
+// The players' rolls are independent, so these are enough to count the wins of the whole game.
+struct PlayerTurns {
+    unfinished: Vec<u128>,
+    finished: Vec<u128>,
+}
+
+impl PlayerTurns {
+    // Fails with the player's turn on which the counts overflow.
+    fn new(start: u64, rules: &Rules, roll_sums: &[(u64, u128)]) -> Result<Self, usize> {
+        let mut turns = PlayerTurns {
+            unfinished: vec![1],
+            finished: vec![0],
+        };
+        // Universe counts keyed by (position, score).
+        let mut states = HashMap::from([((start, 0), 1u128)]);
+        while !states.is_empty() {
+            let turn = turns.finished.len();
+            let mut next = HashMap::new();
+            let mut finished = 0u128;
+            for ((pos, score), n) in states {
+                for &(roll, m) in roll_sums {
+                    let pos = ((pos + roll - 1) % rules.board_size) + 1;
+                    let universes = n.checked_mul(m).ok_or(turn)?;
+                    let count = if score + pos >= rules.target {
+                        &mut finished
+                    } else {
+                        next.entry((pos, score + pos)).or_insert(0)
+                    };
+                    *count = count.checked_add(universes).ok_or(turn)?;
+                }
+            }
+            let unfinished = (next.values())
+                .try_fold(0u128, |t, &n| t.checked_add(n))
+                .ok_or(turn)?;
+            turns.unfinished.push(unfinished);
+            turns.finished.push(finished);
+            states = next;
+        }
+        Ok(turns)
+    }
+
+    fn unfinished(&self, turn: usize) -> u128 {
+        self.unfinished.get(turn).copied().unwrap_or(0)
+    }
+}
+
+struct QuantumOutcome {
+    num_players: usize,
+    // Universes created by a single turn's rolls.
+    turn_universes: u128,
+    // Universes in which the game ends on each turn, counting every player's move as a turn.
+    lengths: Vec<u128>,
+}
+
+impl QuantumOutcome {
+    fn new(d: &DiracDice, rules: &Rules) -> Result<Self, Box<dyn Error>> {
+        let roll_sums = rules.roll_sums()?;
+        let num_players = d.positions.len();
+        let players = (d.positions.iter().enumerate())
+            .map(|(p, &pos)| {
+                PlayerTurns::new(pos, rules, &roll_sums).map_err(|turn| {
+                    let game_turn = (turn - 1) * num_players + p + 1;
+                    format!("universe counts overflow on turn {}", game_turn)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let max_turns = players.iter().map(|t| t.finished.len()).max().unwrap_or(0);
+        let mut lengths = vec![0u128; max_turns * num_players + 1];
+        for (p, player) in players.iter().enumerate() {
+            for (turn, &finished) in player.finished.iter().enumerate().skip(1) {
+                // Players before p have had as many turns as p, and players after p one fewer.
+                let game_turn = (turn - 1) * num_players + p + 1;
+                let overflow = || format!("universe counts overflow on turn {}", game_turn);
+                let universes = (players.iter().enumerate())
+                    .filter(|&(q, _)| q != p)
+                    .map(|(q, other)| other.unfinished(if q < p { turn } else { turn - 1 }))
+                    .try_fold(finished, |t, n| t.checked_mul(n))
+                    .ok_or_else(overflow)?;
+                lengths[game_turn] =
+                    (lengths[game_turn].checked_add(universes)).ok_or_else(overflow)?;
+            }
+        }
+        while lengths.len() > 1 && lengths[lengths.len() - 1] == 0 {
+            lengths.pop();
         }
-        d.roll_p2(die.roll() + die.roll() + die.roll());
-        if d.p2_points >= 1000 {
-            val = d.p1_points * die.num_rolls;
-            break;
+        // The roll sums were added up without overflowing, so their total fits too.
+        let turn_universes = roll_sums.iter().map(|&(_, n)| n).sum();
+        Ok(QuantumOutcome {
+            num_players,
+            turn_universes,
+            lengths,
+        })
+    }
+
+    fn wins(&self) -> Result<Vec<u128>, Box<dyn Error>> {
+        let mut wins = vec![0u128; self.num_players];
+        for (turn, &n) in self.lengths.iter().enumerate().skip(1) {
+            let w = &mut wins[(turn - 1) % self.num_players];
+            *w = (w.checked_add(n)).ok_or(format!("win counts overflow on turn {}", turn))?;
+        }
+        Ok(wins)
+    }
+
+    // Games that end sooner split into fewer universes, so each of their universes is weighted by
+    // the probability of its rolls rather than counted equally.
+    fn win_probabilities(&self) -> Vec<f64> {
+        let mut probs = vec![0.0; self.num_players];
+        let mut weight = 1.0;
+        for (turn, &n) in self.lengths.iter().enumerate().skip(1) {
+            weight /= self.turn_universes as f64;
+            probs[(turn - 1) % self.num_players] += n as f64 * weight;
         }
+        probs
     }
-    Ok(val.to_string())
+}
+
+pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
+    let d: DiracDice = std::fs::read_to_string(input)?.parse()?;
+    DETERMINISTIC_RULES.validate(&d)?;
+    Ok(play_deterministic(d, &DETERMINISTIC_RULES)
+        .answer()
+        .to_string())
 }
 
 pub fn prob_2(input: &str) -> Result<String, Box<dyn Error>> {
     let d: DiracDice = std::fs::read_to_string(input)?.parse()?;
-    let mut wins = [0; 2];
-    roll_quantum_die(&mut wins, &d, false, 1);
-    Ok(wins[0].max(wins[1]).to_string())
+    QUANTUM_RULES.validate(&d)?;
+    let wins = QuantumOutcome::new(&d, &QUANTUM_RULES)?.wins()?;
+    Ok(wins.into_iter().max().unwrap_or(0).to_string())
+}
+
+fn outcome(d: DiracDice, problem: i32, rules: &Rules) -> Result<String, Box<dyn Error>> {
+    rules.validate(&d)?;
+    if problem == 1 {
+        let outcome = play_deterministic(d, rules);
+        return Ok(format!("{}answer: {}\n", outcome, outcome.answer()));
+    }
+    let wins = QuantumOutcome::new(&d, rules)?.wins()?;
+    Ok((wins.iter().enumerate())
+        .map(|(p, w)| format!("player {} wins: {}\n", p + 1, w))
+        .collect())
+}
+
+// Reports each player's outcome in either game.
+pub fn players(input: &str, problem: i32) -> Result<String, Box<dyn Error>> {
+    let d: DiracDice = std::fs::read_to_string(input)?.parse()?;
+    let rules = if problem == 1 {
+        DETERMINISTIC_RULES
+    } else {
+        QUANTUM_RULES
+    };
+    outcome(d, problem, &rules)
+}
+
+// Reports each player's outcome in either game played with custom rules.
+pub fn play_with_rules(
+    input: &str,
+    problem: i32,
+    board_size: u64,
+    target: u64,
+    die_faces: u64,
+    rolls_per_turn: u64,
+) -> Result<String, Box<dyn Error>> {
+    let d: DiracDice = std::fs::read_to_string(input)?.parse()?;
+    let rules = Rules {
+        board_size,
+        target,
+        die_faces,
+        rolls_per_turn,
+    };
+    outcome(d, problem, &rules)
+}
+
+// Reports the number of universes in which the quantum game ends on each turn.
+pub fn game_lengths(input: &str) -> Result<String, Box<dyn Error>> {
+    let d: DiracDice = std::fs::read_to_string(input)?.parse()?;
+    QUANTUM_RULES.validate(&d)?;
+    let outcome = QuantumOutcome::new(&d, &QUANTUM_RULES)?;
+    Ok((outcome.lengths.iter().enumerate())
+        .filter(|&(_, &n)| n > 0)
+        .map(|(turn, n)| {
+            let player = (turn - 1) % outcome.num_players + 1;
+            format!("turn {} (player {} wins): {}\n", turn, player, n)
+        })
+        .collect())
+}
+
+// Tabulates player 1's chance of winning the two-player quantum game for every pair of starting
+// positions, with player 1's start down the side and player 2's across the top.
+pub fn win_table() -> Result<String, Box<dyn Error>> {
+    let board_size = QUANTUM_RULES.board_size;
+    let mut table = String::from("  ");
+    for p2 in 1..=board_size {
+        table += &format!(" {:>6}", p2);
+    }
+    table += "\n";
+    for p1 in 1..=board_size {
+        table += &format!("{:>2}", p1);
+        for p2 in 1..=board_size {
+            let d = DiracDice {
+                positions: vec![p1, p2],
+                points: vec![0; 2],
+                turn: 0,
+            };
+            let probs = QuantumOutcome::new(&d, &QUANTUM_RULES)?.win_probabilities();
+            table += &format!(" {:>6.4}", probs[0]);
+        }
+        table += "\n";
+    }
+    Ok(table)
 }
@@ -1,515 +1,533 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 
-const HALLWAY_LOCS: [usize; 7] = [0, 1, 3, 5, 7, 9, 10];
+// Amber amphipods are type 0, Bronze type 1 and so on, with each type expected
+// in the room of the same index.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct Amphipod(u8);
 
-#[derive(Copy, Clone, Eq, PartialEq)]
-enum Room {
-    Room1,
-    Room2,
-    Room3,
-    Room4,
-}
+impl FromStr for Amphipod {
+    type Err = Box<dyn Error>;
 
-impl Room {
-    fn expected_amphipod(self) -> Amphipod {
-        match self {
-            Room::Room1 => Amphipod::Amber,
-            Room::Room2 => Amphipod::Bronze,
-            Room::Room3 => Amphipod::Copper,
-            Room::Room4 => Amphipod::Desert,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [c @ b'A'..=b'Z'] => Ok(Amphipod(c - b'A')),
+            _ => Err(format!("invalid amphipod: {}", s).into()),
         }
     }
+}
 
-    fn pos(self) -> usize {
-        match self {
-            Room::Room1 => 2,
-            Room::Room2 => 4,
-            Room::Room3 => 6,
-            Room::Room4 => 8,
-        }
+impl Amphipod {
+    fn expected_room(self) -> usize {
+        self.0 as usize
     }
 
-    fn dist(self, room_pos: usize, hallway_pos: usize) -> usize {
-        let mut d = room_pos + 1;
-        d += ((hallway_pos as i64) - (self.pos() as i64)).abs() as usize;
-        d
+    fn energy(self, steps: usize) -> usize {
+        steps * 10usize.pow(self.0 as u32)
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq)]
-enum Amphipod {
-    Amber,
-    Bronze,
-    Copper,
-    Desert,
+impl fmt::Display for Amphipod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", (b'A' + self.0) as char)
+    }
 }
 
-impl FromStr for Amphipod {
-    type Err = Box<dyn Error>;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "A" => Ok(Amphipod::Amber),
-            "B" => Ok(Amphipod::Bronze),
-            "C" => Ok(Amphipod::Copper),
-            "D" => Ok(Amphipod::Desert),
-            _ => Err(format!("invalid amphipod: {}", s).into()),
-        }
-    }
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Cell {
+    Hallway(usize),
+    // Room index and depth below the doorway.
+    Room(usize, usize),
 }
 
-impl Amphipod {
-    fn expected_room(self) -> Room {
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Amphipod::Amber => Room::Room1,
-            Amphipod::Bronze => Room::Room2,
-            Amphipod::Copper => Room::Room3,
-            Amphipod::Desert => Room::Room4,
+            Cell::Hallway(h) => write!(f, "hallway {}", h),
+            Cell::Room(r, i) => write!(f, "room {} depth {}", r, i),
         }
     }
+}
 
-    fn energy(self, steps: usize) -> usize {
-        match self {
-            Amphipod::Amber => steps,
-            Amphipod::Bronze => steps * 10,
-            Amphipod::Copper => steps * 100,
-            Amphipod::Desert => steps * 1000,
-        }
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Move {
+    amphipod: Amphipod,
+    from: Cell,
+    to: Cell,
+    energy: usize,
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} moves from {} to {} using {} energy",
+            self.amphipod, self.from, self.to, self.energy
+        )
     }
 }
 
-#[derive(Copy, Clone)]
-struct Burrow {
-    room_1: [Option<Amphipod>; 2],
-    room_2: [Option<Amphipod>; 2],
-    room_3: [Option<Amphipod>; 2],
-    room_4: [Option<Amphipod>; 2],
-    hallway: [Option<Amphipod>; 11],
-    energy_spent: usize,
+// The parts of a burrow that don't change as amphipods move around.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Layout {
+    hallway_len: usize,
+    // The hallway cell just outside each room. Amphipods never stop on these.
+    doors: Vec<usize>,
 }
 
-#[derive(Copy, Clone)]
-struct BurrowExpanded {
-    room_1: [Option<Amphipod>; 4],
-    room_2: [Option<Amphipod>; 4],
-    room_3: [Option<Amphipod>; 4],
-    room_4: [Option<Amphipod>; 4],
-    hallway: [Option<Amphipod>; 11],
-    energy_spent: usize,
+// Where every amphipod currently is. Rooms are listed from the doorway down.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct State {
+    hallway: Vec<Option<Amphipod>>,
+    rooms: Vec<Vec<Option<Amphipod>>>,
 }
 
-impl FromStr for Burrow {
-    type Err = Box<dyn Error>;
+impl State {
+    fn is_arranged(&self) -> bool {
+        self.rooms
+            .iter()
+            .enumerate()
+            .all(|(r, ro)| ro.iter().all(|a| a.is_some_and(|a| a.expected_room() == r)))
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let err_invalid_input = Err("invalid input".into());
-        let mut lines = s.lines();
-        if lines.clone().count() != 5 {
-            return err_invalid_input;
-        }
-        // Skip first 2 lines.
-        lines.next();
-        lines.next();
-        let top_row = lines
-            .next()
-            .unwrap()
-            .split("#")
-            .filter_map(|p| {
-                let q = p.trim();
-                if q.is_empty() {
-                    None
-                } else {
-                    Some(q)
-                }
-            })
+    // Whether a room only holds amphipods that belong there, so that more of
+    // them can move in.
+    fn is_settled(&self, room: usize) -> bool {
+        self.rooms[room]
+            .iter()
+            .all(|a| a.is_none_or(|a| a.expected_room() == room))
+    }
+
+    // Whether an amphipod can walk along the hallway from `from` to `to`. The
+    // starting cell itself isn't checked since the amphipod may be standing on it.
+    fn is_clear(&self, from: usize, to: usize) -> bool {
+        let path = if from < to {
+            from + 1..to + 1
+        } else {
+            to..from
+        };
+        self.hallway[path].iter().all(|c| c.is_none())
+    }
+
+    // Two amphipods in the hallway that each need to walk past the other to get
+    // home can never move again, since amphipods only leave the hallway for
+    // their own room.
+    fn is_deadlocked(&self, layout: &Layout) -> bool {
+        let waiting = self
+            .hallway
+            .iter()
+            .enumerate()
+            .filter_map(|(h, a)| a.map(|a| (h, layout.doors.get(a.expected_room()))))
             .collect::<Vec<_>>();
-        if top_row.len() != 4 {
-            return err_invalid_input;
-        }
-        let bot_row = lines
-            .next()
-            .unwrap()
-            .split("#")
-            .filter_map(|p| {
-                let q = p.trim();
-                if q.is_empty() {
-                    None
-                } else {
-                    Some(q)
-                }
+        waiting.iter().enumerate().any(|(i, &(x, dx))| {
+            waiting[i + 1..].iter().any(|&(y, dy)| match (dx, dy) {
+                (Some(&dx), Some(&dy)) => dx >= y && dy <= x,
+                _ => false,
             })
-            .collect::<Vec<_>>();
-        if bot_row.len() != 4 {
-            return err_invalid_input;
-        }
-        Ok(Burrow {
-            room_1: [Some(top_row[0].parse()?), Some(bot_row[0].parse()?)],
-            room_2: [Some(top_row[1].parse()?), Some(bot_row[1].parse()?)],
-            room_3: [Some(top_row[2].parse()?), Some(bot_row[2].parse()?)],
-            room_4: [Some(top_row[3].parse()?), Some(bot_row[3].parse()?)],
-            hallway: Default::default(),
-            energy_spent: Default::default(),
         })
     }
-}
 
-impl Burrow {
-    fn to_expanded(&self) -> BurrowExpanded {
-        BurrowExpanded {
-            room_1: [
-                self.room_1[0],
-                Some(Amphipod::Desert),
-                Some(Amphipod::Desert),
-                self.room_1[1],
-            ],
-            room_2: [
-                self.room_2[0],
-                Some(Amphipod::Copper),
-                Some(Amphipod::Bronze),
-                self.room_2[1],
-            ],
-            room_3: [
-                self.room_3[0],
-                Some(Amphipod::Bronze),
-                Some(Amphipod::Amber),
-                self.room_3[1],
-            ],
-            room_4: [
-                self.room_4[0],
-                Some(Amphipod::Amber),
-                Some(Amphipod::Copper),
-                self.room_4[1],
-            ],
-            hallway: self.hallway,
-            energy_spent: self.energy_spent,
+    // Where an amphipod walking from hallway cell `from` would end up in its own
+    // room, along with the number of steps it takes to get there.
+    fn enter_room(&self, layout: &Layout, am: Amphipod, from: usize) -> Option<(usize, usize)> {
+        let r = am.expected_room();
+        if r >= self.rooms.len() || !self.is_settled(r) {
+            return None;
+        }
+        let door = layout.doors[r];
+        if !self.is_clear(from, door) {
+            return None;
         }
+        let depth = self.rooms[r].iter().rposition(|c| c.is_none())?;
+        Some((depth, from.max(door) - from.min(door) + depth + 1))
     }
 
-    fn get_room(&self, room: Room) -> &[Option<Amphipod>; 2] {
-        match room {
-            Room::Room1 => &self.room_1,
-            Room::Room2 => &self.room_2,
-            Room::Room3 => &self.room_3,
-            Room::Room4 => &self.room_4,
+    fn set(&mut self, c: Cell, a: Option<Amphipod>) {
+        match c {
+            Cell::Hallway(h) => self.hallway[h] = a,
+            Cell::Room(r, i) => self.rooms[r][i] = a,
         }
     }
-}
 
-impl BurrowExpanded {
-    fn get_room(&self, room: Room) -> &[Option<Amphipod>; 4] {
-        match room {
-            Room::Room1 => &self.room_1,
-            Room::Room2 => &self.room_2,
-            Room::Room3 => &self.room_3,
-            Room::Room4 => &self.room_4,
-        }
+    fn apply(&self, m: &Move) -> State {
+        let mut new = self.clone();
+        new.set(m.from, None);
+        new.set(m.to, Some(m.amphipod));
+        new
     }
-}
 
-fn move_amphipod(
-    burrow: Burrow,
-    from_hallway: bool,
-    room: Room,
-    old_pos: usize,
-    new_pos: usize,
-) -> Option<Burrow> {
-    let mut new = burrow.clone();
-    let am: Amphipod;
-    let ro = match room {
-        Room::Room1 => &mut new.room_1,
-        Room::Room2 => &mut new.room_2,
-        Room::Room3 => &mut new.room_3,
-        Room::Room4 => &mut new.room_4,
-    };
-    let room_pos = room.pos();
-    if from_hallway {
-        am = new.hallway[old_pos].unwrap();
-        // Check if there is a clear path.
-        if old_pos < room_pos {
-            for i in (old_pos + 1)..=room_pos {
-                if new.hallway[i].is_some() {
-                    return None;
+    fn moves(&self, layout: &Layout) -> Vec<Move> {
+        let mut moves = Vec::new();
+        // Amphipods in the hallway can only move into their own room.
+        for (h, a) in self.hallway.iter().enumerate() {
+            if let Some(am) = *a {
+                if let Some((depth, steps)) = self.enter_room(layout, am, h) {
+                    moves.push(Move {
+                        amphipod: am,
+                        from: Cell::Hallway(h),
+                        to: Cell::Room(am.expected_room(), depth),
+                        energy: am.energy(steps),
+                    });
                 }
             }
-        } else {
-            for i in room_pos..old_pos {
-                if new.hallway[i].is_some() {
-                    return None;
-                }
-            }
-        }
-        for i in 0..=new_pos {
-            if ro[i].is_some() {
-                return None;
-            }
         }
-        // Move the amphipod.
-        ro[new_pos] = Some(am);
-        new.hallway[old_pos] = None;
-        new.energy_spent += am.energy(room.dist(new_pos, old_pos));
-    } else {
-        am = ro[old_pos].unwrap();
-        // Check if there is a clear path.
-        if old_pos > 0 {
-            for i in 0..old_pos {
-                if ro[i].is_some() {
-                    return None;
-                }
+        // The topmost amphipod of a room that still holds strangers has to leave,
+        // either straight into its own room or to a stop in the hallway.
+        for r in 0..self.rooms.len() {
+            if self.is_settled(r) {
+                continue;
             }
-        }
-        if new_pos < room_pos {
-            for i in new_pos..=room_pos {
-                if new.hallway[i].is_some() {
-                    return None;
-                }
+            let i = match self.rooms[r].iter().position(|c| c.is_some()) {
+                Some(i) => i,
+                None => continue,
+            };
+            let am = self.rooms[r][i].unwrap();
+            let door = layout.doors[r];
+            if let Some((depth, steps)) = self.enter_room(layout, am, door) {
+                moves.push(Move {
+                    amphipod: am,
+                    from: Cell::Room(r, i),
+                    to: Cell::Room(am.expected_room(), depth),
+                    energy: am.energy(i + 1 + steps),
+                });
             }
-        } else {
-            for i in room_pos..=new_pos {
-                if new.hallway[i].is_some() {
-                    return None;
+            for h in 0..layout.hallway_len {
+                if layout.doors.contains(&h) || !self.is_clear(door, h) {
+                    continue;
                 }
+                moves.push(Move {
+                    amphipod: am,
+                    from: Cell::Room(r, i),
+                    to: Cell::Hallway(h),
+                    energy: am.energy(i + 1 + h.max(door) - h.min(door)),
+                });
             }
         }
-        // Move the amphipod.
-        new.hallway[new_pos] = Some(am);
-        ro[old_pos] = None;
-        new.energy_spent += am.energy(room.dist(old_pos, new_pos));
+        moves
     }
-    Some(new)
-}
 
-fn move_amphipod_expanded(
-    burrow: BurrowExpanded,
-    from_hallway: bool,
-    room: Room,
-    old_pos: usize,
-    new_pos: usize,
-) -> Option<BurrowExpanded> {
-    let mut new = burrow.clone();
-    let am: Amphipod;
-    let ro = match room {
-        Room::Room1 => &mut new.room_1,
-        Room::Room2 => &mut new.room_2,
-        Room::Room3 => &mut new.room_3,
-        Room::Room4 => &mut new.room_4,
-    };
-    let room_pos = room.pos();
-    if from_hallway {
-        am = new.hallway[old_pos].unwrap();
-        // Check if there is a clear path.
-        if old_pos < room_pos {
-            for i in (old_pos + 1)..=room_pos {
-                if new.hallway[i].is_some() {
-                    return None;
-                }
-            }
-        } else {
-            for i in room_pos..old_pos {
-                if new.hallway[i].is_some() {
-                    return None;
-                }
-            }
-        }
-        for i in 0..=new_pos {
-            if ro[i].is_some() {
-                return None;
-            }
-        }
-        // Move the amphipod.
-        ro[new_pos] = Some(am);
-        new.hallway[old_pos] = None;
-        new.energy_spent += am.energy(room.dist(new_pos, old_pos));
-    } else {
-        am = ro[old_pos].unwrap();
-        // Check if there is a clear path.
-        if old_pos > 0 {
-            for i in 0..old_pos {
-                if ro[i].is_some() {
-                    return None;
-                }
-            }
-        }
-        if new_pos < room_pos {
-            for i in new_pos..=room_pos {
-                if new.hallway[i].is_some() {
-                    return None;
-                }
-            }
-        } else {
-            for i in room_pos..=new_pos {
-                if new.hallway[i].is_some() {
-                    return None;
-                }
-            }
+    // Draws the state as a burrow diagram in the puzzle's format.
+    fn render(&self, layout: &Layout) -> String {
+        let width = layout.hallway_len + 2;
+        let cell = |a: Option<Amphipod>| a.map_or('.', |a| (b'A' + a.0) as char);
+        let mut out = format!("{}\n", "#".repeat(width));
+        let hallway = self.hallway.iter().map(|&a| cell(a));
+        out += &format!("#{}#\n", hallway.collect::<String>());
+        // Below the top row of rooms, the walls only surround the rooms themselves.
+        let left = layout.doors.iter().min().map_or(0, |d| *d);
+        let right = layout.doors.iter().max().map_or(0, |d| d + 2);
+        let depth = self.rooms.first().map_or(0, |ro| ro.len());
+        for i in 0..=depth {
+            let row = (0..width)
+                .map(|c| {
+                    if i < depth && c > 0 {
+                        if let Some(r) = layout.doors.iter().position(|&d| d == c - 1) {
+                            return cell(self.rooms[r][i]);
+                        }
+                    }
+                    if i == 0 || (left..=right).contains(&c) {
+                        '#'
+                    } else {
+                        ' '
+                    }
+                })
+                .collect::<String>();
+            out += &format!("{}\n", row.trim_end());
         }
-        // Move the amphipod.
-        new.hallway[new_pos] = Some(am);
-        ro[old_pos] = None;
-        new.energy_spent += am.energy(room.dist(old_pos, new_pos));
+        out
     }
-    Some(new)
 }
 
-fn rearrange(burrow: Burrow, min_energy: &mut usize) {
-    if burrow.energy_spent >= *min_energy {
-        return;
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Burrow {
+    layout: Layout,
+    start: State,
+}
+
+fn parse_cell(c: char) -> Result<Option<Amphipod>, Box<dyn Error>> {
+    match c {
+        '.' => Ok(None),
+        _ => Ok(Some(c.to_string().parse()?)),
     }
-    // Check if already arranged.
-    let mut already_arranged = true;
-    for r in [Room::Room1, Room::Room2, Room::Room3, Room::Room4] {
-        let ro = burrow.get_room(r);
-        for a in ro.iter() {
-            if a.is_none() || a.unwrap().expected_room() != r {
-                already_arranged = false;
-                break;
-            }
+}
+
+impl FromStr for Burrow {
+    type Err = Box<dyn Error>;
+
+    // Reads any burrow diagram: the hallway is the open row below the top wall,
+    // and every open column below it is a room of the same depth.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let grid = s
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| l.chars().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let is_open = |c: &char| *c == '.' || c.is_ascii_uppercase();
+        let open_cols = |row: &[char]| {
+            row.iter()
+                .enumerate()
+                .filter(|(_, c)| is_open(c))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+        if grid.len() < 3 {
+            return Err("burrow needs a hallway with rooms below it".into());
         }
-        if already_arranged == false {
-            break;
+        let hallway_cols = open_cols(&grid[1]);
+        let first = match hallway_cols.first() {
+            Some(&c) => c,
+            None => return Err("burrow has no hallway".into()),
+        };
+        if hallway_cols
+            .iter()
+            .enumerate()
+            .any(|(i, &c)| c != first + i)
+        {
+            return Err("hallway must be a single unbroken row".into());
         }
-    }
-    if already_arranged {
-        *min_energy = (*min_energy).min(burrow.energy_spent);
-        return;
-    }
-    // Check rooms.
-    for r in [Room::Room1, Room::Room2, Room::Room3, Room::Room4] {
-        let ro = burrow.get_room(r);
-        for i in 0..=1 {
-            if ro[i].is_some() {
-                let am = ro[i].unwrap();
-                let try_move: bool;
-                if i == 1 {
-                    try_move = am != r.expected_amphipod();
-                } else {
-                    try_move = (am != r.expected_amphipod())
-                        || (ro[1].is_some() && ro[1].unwrap() != r.expected_amphipod());
-                }
-                if try_move {
-                    for j in HALLWAY_LOCS {
-                        match move_amphipod(burrow, false, r, i, j) {
-                            Some(new_burrow) => rearrange(new_burrow, min_energy),
-                            None => (),
-                        }
-                    }
+        let hallway = hallway_cols
+            .iter()
+            .map(|&c| parse_cell(grid[1][c]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut room_cols: Vec<usize> = Vec::new();
+        let mut room_start = 0;
+        let mut rooms: Vec<Vec<Option<Amphipod>>> = Vec::new();
+        for (i, row) in grid[2..].iter().enumerate() {
+            let cols = open_cols(row);
+            if cols.is_empty() {
+                continue;
+            }
+            if rooms.is_empty() {
+                if let Some(c) = cols.iter().find(|c| !hallway_cols.contains(c)) {
+                    return Err(format!("room at column {} isn't below the hallway", c).into());
                 }
+                room_cols = cols.clone();
+                room_start = i;
+                rooms = vec![Vec::new(); cols.len()];
+            } else if cols != room_cols || room_start + rooms[0].len() != i {
+                return Err(
+                    format!("line {} doesn't line up with the rooms above it", i + 3).into(),
+                );
             }
-        }
-    }
-    // Check hallway.
-    for i in HALLWAY_LOCS {
-        if burrow.hallway[i].is_some() {
-            let am = burrow.hallway[i].unwrap();
-            let r = am.expected_room();
-            let ro = burrow.get_room(r);
-            let j = if ro[1].is_some() && ro[1].unwrap() == r.expected_amphipod() {
-                0
-            } else {
-                1
-            };
-            match move_amphipod(burrow, true, r, i, j) {
-                Some(new_burrow) => rearrange(new_burrow, min_energy),
-                None => (),
+            for (ro, &c) in rooms.iter_mut().zip(cols.iter()) {
+                ro.push(parse_cell(row[c])?);
             }
         }
+        if rooms.is_empty() {
+            return Err("burrow has no rooms".into());
+        }
+        // Amphipods never stop outside a room, and moves out of a room assume
+        // the doorway is free.
+        if let Some(&c) = room_cols.iter().find(|&&c| grid[1][c] != '.') {
+            return Err(format!(
+                "amphipod {} at line 2, column {} is standing in a doorway",
+                grid[1][c],
+                c + 1
+            )
+            .into());
+        }
+        Ok(Burrow {
+            layout: Layout {
+                hallway_len: hallway.len(),
+                doors: room_cols.iter().map(|c| c - first).collect(),
+            },
+            start: State { hallway, rooms },
+        })
     }
 }
 
-fn rearrange_expanded(burrow: BurrowExpanded, min_energy: &mut usize) {
-    if burrow.energy_spent >= *min_energy {
-        return;
-    }
-    // Check if already arranged.
-    let mut already_arranged = true;
-    for r in [Room::Room1, Room::Room2, Room::Room3, Room::Room4] {
-        let ro = burrow.get_room(r);
-        for a in ro.iter() {
-            if a.is_none() || a.unwrap().expected_room() != r {
-                already_arranged = false;
-                break;
+impl Burrow {
+    // Checks for burrows that can't be solved whatever the moves: each room
+    // must end up full of its own amphipods, and none may start out deadlocked.
+    fn check_solvable(&self) -> Result<(), Box<dyn Error>> {
+        let rooms = &self.start.rooms;
+        let depth = rooms.first().map_or(0, |ro| ro.len());
+        let mut counts = vec![0; rooms.len()];
+        for a in self
+            .start
+            .hallway
+            .iter()
+            .chain(rooms.iter().flatten())
+            .flatten()
+        {
+            match counts.get_mut(a.expected_room()) {
+                Some(n) => *n += 1,
+                None => return Err(format!("amphipod {} has no room to go to", a).into()),
             }
         }
-        if already_arranged == false {
-            break;
+        let wrong = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n != depth)
+            .map(|(r, n)| format!("{} {}", n, Amphipod(r as u8)))
+            .collect::<Vec<_>>();
+        if !wrong.is_empty() {
+            return Err(format!(
+                "expected {} of each amphipod but found {}",
+                depth,
+                wrong.join(", ")
+            )
+            .into());
         }
+        if self.start.is_deadlocked(&self.layout) {
+            return Err("amphipods in the hallway are blocking each other".into());
+        }
+        Ok(())
     }
-    if already_arranged {
-        *min_energy = (*min_energy).min(burrow.energy_spent);
-        return;
-    }
-    // Check rooms.
-    for r in [Room::Room1, Room::Room2, Room::Room3, Room::Room4] {
-        let ro = burrow.get_room(r);
-        for i in 0..=3 {
-            if ro[i].is_some() {
-                let am = ro[i].unwrap();
-                let try_move: bool;
-                if i == 3 {
-                    try_move = am != r.expected_amphipod();
-                } else if i == 2 {
-                    try_move = (am != r.expected_amphipod())
-                        || (ro[3].is_some() && ro[3].unwrap() != r.expected_amphipod());
-                } else if i == 1 {
-                    try_move = (am != r.expected_amphipod())
-                        || (ro[3].is_some() && ro[3].unwrap() != r.expected_amphipod())
-                        || (ro[2].is_some() && ro[2].unwrap() != r.expected_amphipod());
-                } else {
-                    try_move = (am != r.expected_amphipod())
-                        || (ro[3].is_some() && ro[3].unwrap() != r.expected_amphipod())
-                        || (ro[2].is_some() && ro[2].unwrap() != r.expected_amphipod())
-                        || (ro[1].is_some() && ro[1].unwrap() != r.expected_amphipod());
+
+    // Dijkstra's algorithm over burrow states, returning the moves that get
+    // every amphipod into its own room using the least energy.
+    fn rearrange(&self) -> Result<Vec<Move>, Box<dyn Error>> {
+        self.check_solvable()?;
+        let mut best: HashMap<State, usize> = HashMap::new();
+        let mut prev: HashMap<State, (State, Move)> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        best.insert(self.start.clone(), 0);
+        queue.push(Reverse((0, self.start.clone())));
+        while let Some(Reverse((energy, state))) = queue.pop() {
+            if state.is_arranged() {
+                let mut moves = Vec::new();
+                let mut s = &state;
+                while let Some((p, m)) = prev.get(s) {
+                    moves.push(*m);
+                    s = p;
                 }
-                if try_move {
-                    for j in HALLWAY_LOCS {
-                        match move_amphipod_expanded(burrow, false, r, i, j) {
-                            Some(new_burrow) => rearrange_expanded(new_burrow, min_energy),
-                            None => (),
-                        }
-                    }
+                moves.reverse();
+                return Ok(moves);
+            }
+            if best.get(&state).is_some_and(|&e| e < energy) {
+                continue;
+            }
+            for m in state.moves(&self.layout) {
+                let new = state.apply(&m);
+                if new.is_deadlocked(&self.layout) {
+                    continue;
+                }
+                let e = energy + m.energy;
+                if best.get(&new).is_none_or(|&b| e < b) {
+                    best.insert(new.clone(), e);
+                    prev.insert(new.clone(), (state.clone(), m));
+                    queue.push(Reverse((e, new)));
                 }
             }
         }
+        Err("no sequence of moves rearranges the amphipods".into())
     }
-    // Check hallway.
-    for i in HALLWAY_LOCS {
-        if burrow.hallway[i].is_some() {
-            let am = burrow.hallway[i].unwrap();
-            let r = am.expected_room();
-            let ro = burrow.get_room(r);
-            let j = if ro[3].is_some() && ro[3].unwrap() == r.expected_amphipod() {
-                if ro[2].is_some() && ro[2].unwrap() == r.expected_amphipod() {
-                    if ro[1].is_some() && ro[1].unwrap() == r.expected_amphipod() {
-                        0
-                    } else {
-                        1
-                    }
-                } else {
-                    2
-                }
-            } else {
-                3
-            };
-            match move_amphipod_expanded(burrow, true, r, i, j) {
-                Some(new_burrow) => rearrange_expanded(new_burrow, min_energy),
-                None => (),
-            }
+}
+
+// Part 2 unfolds the diagram, inserting these rows below the first row of rooms.
+const UNFOLDED_ROWS: [&str; 2] = ["  #D#C#B#A#", "  #D#B#A#C#"];
+
+fn read_burrow(input: &str, unfold: bool) -> Result<Burrow, Box<dyn Error>> {
+    parse_burrow(&std::fs::read_to_string(input)?, unfold)
+}
+
+fn parse_burrow(diagram: &str, unfold: bool) -> Result<Burrow, Box<dyn Error>> {
+    if unfold {
+        let mut lines = diagram.lines().collect::<Vec<_>>();
+        if lines.len() < 3 {
+            return Err("burrow is too small to unfold".into());
         }
+        lines.splice(3..3, UNFOLDED_ROWS);
+        lines.join("\n").parse()
+    } else {
+        diagram.parse()
     }
 }
 
 pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
-    let burrow: Burrow = std::fs::read_to_string(input)?.parse()?;
-    let mut min_energy = usize::MAX;
-    rearrange(burrow, &mut min_energy);
+    let burrow = read_burrow(input, false)?;
+    let min_energy: usize = burrow.rearrange()?.iter().map(|m| m.energy).sum();
     Ok(min_energy.to_string())
 }
 
 pub fn prob_2(input: &str) -> Result<String, Box<dyn Error>> {
-    let burrow: BurrowExpanded = std::fs::read_to_string(input)?
-        .parse::<Burrow>()?
-        .to_expanded();
-    let mut min_energy = usize::MAX;
-    rearrange_expanded(burrow, &mut min_energy);
+    let burrow = read_burrow(input, true)?;
+    let min_energy: usize = burrow.rearrange()?.iter().map(|m| m.energy).sum();
     Ok(min_energy.to_string())
 }
+
+// Prints every intermediate burrow along the optimal solution. With a delay,
+// the terminal is cleared before each step so that the replay is animated.
+pub fn replay(input: &str, unfold: bool, delay_ms: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let burrow = read_burrow(input, unfold)?;
+    let moves = burrow.rearrange()?;
+    let mut state = burrow.start.clone();
+    let mut total = 0;
+    for (i, m) in [None].into_iter().chain(moves.iter().map(Some)).enumerate() {
+        if let Some(m) = m {
+            state = state.apply(m);
+            total += m.energy;
+        }
+        // Leave the previous frame up for the delay before clearing it.
+        if let Some(ms) = delay_ms {
+            if i > 0 {
+                std::thread::sleep(std::time::Duration::from_millis(ms));
+            }
+            print!("\x1b[2J\x1b[H");
+        }
+        match m {
+            Some(m) => println!("Step {}: {} (total {})", i, m, total),
+            None => println!("Start"),
+        }
+        println!("{}", state.render(&burrow.layout));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_amphipod_in_doorway() {
+        let diagram = "#############\n#....A......#\n###.#C#B#D###\n  #A#B#C#D#\n  #########\n";
+        let err = diagram.parse::<Burrow>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "amphipod A at line 2, column 6 is standing in a doorway"
+        );
+    }
+
+    const EXAMPLE: &str = "#############\n#...........#\n###B#C#B#D###\n  #A#D#C#A#\n  #########\n";
+
+    fn min_energy(diagram: &str, unfold: bool) -> usize {
+        let burrow = parse_burrow(diagram, unfold).unwrap();
+        burrow.rearrange().unwrap().iter().map(|m| m.energy).sum()
+    }
+
+    #[test]
+    fn solves_example() {
+        assert_eq!(min_energy(EXAMPLE, false), 12521);
+        assert_eq!(min_energy(EXAMPLE, true), 44169);
+    }
+
+    #[test]
+    fn rejects_hallway_deadlock() {
+        let diagram = "#############\n#...D.A.....#\n###.#B#C#.###\n  #A#B#C#D#\n  #########\n";
+        let err = parse_burrow(diagram, false)
+            .unwrap()
+            .rearrange()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "amphipods in the hallway are blocking each other"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_amphipod_counts() {
+        let diagram = "#############\n#...........#\n###B#C#B#D###\n  #B#D#C#A#\n  #########\n";
+        let err = parse_burrow(diagram, false)
+            .unwrap()
+            .rearrange()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected 2 of each amphipod but found 1 A, 3 B"
+        );
+    }
+}
@@ -1,95 +1,209 @@
 use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum SeaCucumber {
-    East,
-    South,
+// One bit per cell, with each row packed into 64-bit words (bit x of a row is bit x % 64 of word
+// x / 64). Bits past the width of the grid are always clear.
+type Row = Vec<u64>;
+
+fn get(row: &Row, x: usize) -> bool {
+    row[x / 64] & (1 << (x % 64)) != 0
 }
 
-impl FromStr for SeaCucumber {
-    type Err = String;
+fn set(row: &mut Row, x: usize) {
+    row[x / 64] |= 1 << (x % 64);
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            ">" => Ok(SeaCucumber::East),
-            "v" => Ok(SeaCucumber::South),
-            _ => Err("not a sea cucumber".to_string()),
-        }
+fn clear(row: &mut Row, x: usize) {
+    row[x / 64] &= !(1 << (x % 64));
+}
+
+// Bit x of the result is bit x + 1 of the row, wrapping around at the width.
+fn rotate_next(row: &Row, width: usize) -> Row {
+    let mut next: Row = (0..row.len())
+        .map(|i| row[i] >> 1 | row.get(i + 1).map_or(0, |w| w << 63))
+        .collect();
+    if get(row, 0) {
+        set(&mut next, width - 1);
     }
+    next
 }
 
+// Bit x of the result is bit x - 1 of the row, wrapping around at the width.
+fn rotate_prev(row: &Row, width: usize) -> Row {
+    let mut prev: Row = (0..row.len())
+        .map(|i| row[i] << 1 | if i > 0 { row[i - 1] >> 63 } else { 0 })
+        .collect();
+    if !width.is_multiple_of(64) {
+        clear(&mut prev, width);
+    }
+    if get(row, width - 1) {
+        set(&mut prev, 0);
+    }
+    prev
+}
+
+#[derive(Clone)]
 struct SeaFloor {
-    region: Vec<Vec<Option<SeaCucumber>>>,
+    width: usize,
+    east: Vec<Row>,
+    south: Vec<Row>,
 }
 
 impl FromStr for SeaFloor {
-    type Err = String;
+    type Err = Box<dyn Error>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut sea_floor = SeaFloor { region: Vec::new() };
-        for l in s.lines() {
-            sea_floor.region.push(
-                l.chars()
-                    .map(|c| c.to_string().parse::<SeaCucumber>().ok())
-                    .collect(),
-            );
+        // `lines` also strips the '\r' of CRLF line endings.
+        let width = s.lines().next().map_or(0, |l| l.chars().count());
+        if width == 0 {
+            return Err("empty sea floor".into());
+        }
+        let empty: Row = vec![0; width.div_ceil(64)];
+        let mut sea_floor = SeaFloor {
+            width,
+            east: Vec::new(),
+            south: Vec::new(),
+        };
+        for (y, l) in s.lines().enumerate() {
+            let (mut east, mut south) = (empty.clone(), empty.clone());
+            let mut row_width = 0;
+            for (x, c) in l.chars().enumerate() {
+                match c {
+                    '>' if x < width => set(&mut east, x),
+                    'v' if x < width => set(&mut south, x),
+                    '>' | 'v' | '.' => (),
+                    _ => {
+                        let msg =
+                            format!("invalid cell {:?} at row {}, column {}", c, y + 1, x + 1);
+                        return Err(msg.into());
+                    }
+                }
+                row_width += 1;
+            }
+            // Herds wrap around at the edges, so every row must be the same width.
+            if row_width != width {
+                let msg = format!(
+                    "row {} is {} cells wide instead of {}",
+                    y + 1,
+                    row_width,
+                    width
+                );
+                return Err(msg.into());
+            }
+            sea_floor.east.push(east);
+            sea_floor.south.push(south);
         }
         Ok(sea_floor)
     }
 }
 
-impl SeaFloor {
-    fn advance_step(&mut self) -> usize {
-        let mut east_moves = Vec::new();
-        for i in 0..self.region.len() {
-            for j in 0..self.region[i].len() {
-                match self.region[i][j] {
-                    Some(sc) => match sc {
-                        SeaCucumber::East => {
-                            let mut k = j + 1;
-                            if k >= self.region[i].len() {
-                                k -= self.region[i].len();
-                            }
-                            if self.region[i][k].is_none() {
-                                east_moves.push(((i, j), (i, k)));
-                            }
-                        }
-                        _ => (),
-                    },
-                    None => (),
+impl fmt::Display for SeaFloor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (east, south) in self.east.iter().zip(&self.south) {
+            for x in 0..self.width {
+                let c = if get(east, x) {
+                    '>'
+                } else if get(south, x) {
+                    'v'
+                } else {
+                    '.'
                 };
+                write!(f, "{}", c)?;
             }
+            writeln!(f)?;
         }
-        for m in east_moves.iter() {
-            self.region[m.1 .0][m.1 .1] = self.region[m.0 .0][m.0 .1];
-            self.region[m.0 .0][m.0 .1] = None;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Moves {
+    east: usize,
+    south: usize,
+}
+
+impl Moves {
+    fn total(&self) -> usize {
+        self.east + self.south
+    }
+}
+
+// Yields the step number, the moves made and the resulting sea floor for every
+// step, up to and including the first step in which nothing moves.
+struct Steps {
+    sea_floor: SeaFloor,
+    step: usize,
+    stopped: bool,
+}
+
+impl Iterator for Steps {
+    type Item = (usize, Moves, SeaFloor);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stopped {
+            return None;
         }
-        let mut south_moves = Vec::new();
-        for i in 0..self.region.len() {
-            for j in 0..self.region[i].len() {
-                match self.region[i][j] {
-                    Some(sc) => match sc {
-                        SeaCucumber::South => {
-                            let mut k = i + 1;
-                            if k >= self.region.len() {
-                                k -= self.region.len();
-                            }
-                            if self.region[k][j].is_none() {
-                                south_moves.push(((i, j), (k, j)));
-                            }
-                        }
-                        _ => (),
-                    },
-                    None => (),
-                };
+        let moves = self.sea_floor.advance_step();
+        self.step += 1;
+        self.stopped = moves.total() == 0;
+        Some((self.step, moves, self.sea_floor.clone()))
+    }
+}
+
+impl SeaFloor {
+    fn steps(self) -> Steps {
+        Steps {
+            sea_floor: self,
+            step: 0,
+            stopped: false,
+        }
+    }
+
+    fn occupied(&self, y: usize) -> Row {
+        (self.east[y].iter().zip(&self.south[y]))
+            .map(|(e, s)| e | s)
+            .collect()
+    }
+
+    fn advance_step(&mut self) -> Moves {
+        let mut moves = Moves::default();
+        // The east-facing herd moves along each row, one row at a time.
+        for y in 0..self.east.len() {
+            let blocked = rotate_next(&self.occupied(y), self.width);
+            let moving: Row = (self.east[y].iter().zip(&blocked))
+                .map(|(e, b)| e & !b)
+                .collect();
+            let arrived = rotate_prev(&moving, self.width);
+            for (i, e) in self.east[y].iter_mut().enumerate() {
+                *e = (*e & !moving[i]) | arrived[i];
             }
+            moves.east += moving
+                .iter()
+                .map(|w| w.count_ones() as usize)
+                .sum::<usize>();
         }
-        for m in south_moves.iter() {
-            self.region[m.1 .0][m.1 .1] = self.region[m.0 .0][m.0 .1];
-            self.region[m.0 .0][m.0 .1] = None;
+        // The south-facing herd then moves from each row into the next, all at once.
+        let height = self.south.len();
+        let moving: Vec<Row> = (0..height)
+            .map(|y| {
+                let below = self.occupied((y + 1) % height);
+                (self.south[y].iter().zip(&below))
+                    .map(|(s, b)| s & !b)
+                    .collect()
+            })
+            .collect();
+        for y in 0..height {
+            let above = &moving[(y + height - 1) % height];
+            for (i, s) in self.south[y].iter_mut().enumerate() {
+                *s = (*s & !moving[y][i]) | above[i];
+            }
+            moves.south += moving[y]
+                .iter()
+                .map(|w| w.count_ones() as usize)
+                .sum::<usize>();
         }
-        east_moves.len() + south_moves.len()
+        moves
     }
 }
 
@@ -98,9 +212,86 @@ pub fn prob_1(input: &str) -> Result<String, Box<dyn Error>> {
     let mut step = 0;
     loop {
         step += 1;
-        if sea_floor.advance_step() == 0 {
+        if sea_floor.advance_step().total() == 0 {
             break;
         }
     }
     Ok(step.to_string())
 }
+
+// Describes a state the way the puzzle's worked example does.
+fn describe(step: usize, moves: Moves, sea_floor: &SeaFloor) -> String {
+    let heading = match step {
+        0 => "Initial state:".to_string(),
+        1 => "After 1 step:".to_string(),
+        _ => format!("After {} steps:", step),
+    };
+    let mut out = format!("{}\n{}", heading, sea_floor);
+    if step > 0 {
+        out += &format!("({} moved east, {} moved south)\n", moves.east, moves.south);
+    }
+    out
+}
+
+// Prints the initial state, every `every`th state and the state in which
+// nothing moves, or saves each of them to a file in `dir` instead. An `every`
+// of 0 picks just the initial and final states.
+pub fn frames(input: &str, every: usize, dir: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let sea_floor: SeaFloor = std::fs::read_to_string(input)?.parse()?;
+    if let Some(dir) = dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut out = String::new();
+    let mut saved = 0;
+    let initial = (0, Moves::default(), sea_floor.clone());
+    let states = std::iter::once(initial).chain(sea_floor.steps());
+    for (step, moves, state) in states {
+        let settled = step > 0 && moves.total() == 0;
+        if step != 0 && !settled && (every == 0 || step % every != 0) {
+            continue;
+        }
+        match dir {
+            Some(dir) => {
+                let path = format!("{}/step_{:04}.txt", dir, step);
+                std::fs::write(path, describe(step, moves, &state))?;
+                saved += 1;
+            }
+            None => out += &format!("{}\n", describe(step, moves, &state)),
+        }
+    }
+    if let Some(dir) = dir {
+        out += &format!("saved {} states to {}\n", saved, dir);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(s: &str) -> String {
+        s.parse::<SeaFloor>().err().unwrap().to_string()
+    }
+
+    #[test]
+    fn rejects_ragged_rows() {
+        assert_eq!(
+            parse_error("...>\n.v.\n"),
+            "row 2 is 3 cells wide instead of 4"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_cells() {
+        assert_eq!(
+            parse_error("..>.\n.vx.\n"),
+            "invalid cell 'x' at row 2, column 3"
+        );
+    }
+
+    #[test]
+    fn reads_crlf_line_endings() {
+        let sea_floor: SeaFloor = "...>\r\n.v..\r\n".parse().unwrap();
+        assert_eq!(sea_floor.to_string(), "...>\n.v..\n");
+    }
+}
@@ -26,12 +26,93 @@ mod day_9;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        return Err(format!("{} requires exactly 2 args", args[0]).into());
+    if args.len() < 3 {
+        return Err(format!("{} requires at least 2 args", args[0]).into());
     }
 
     let day = args[1].parse::<i32>()?;
     let problem = args[2].parse::<i32>()?;
+    let opts: Vec<&str> = args[3..].iter().map(|s| s.as_str()).collect();
+    if !opts.is_empty() {
+        match (day, problem, opts.as_slice()) {
+            (14, 1 | 2, ["--check"]) => print!("{}", day_14::check("input/day_14.txt")?),
+            (14, 1 | 2, ["--steps", n]) => {
+                print!("{}", day_14::steps("input/day_14.txt", n.parse()?)?)
+            }
+            (14, 1 | 2, ["--histograms", n]) => {
+                print!("{}", day_14::histograms("input/day_14.txt", n.parse()?)?)
+            }
+            (14, 1 | 2, ["--matrix", steps]) => print!(
+                "{}",
+                day_14::counts_mod("input/day_14.txt", steps.parse()?, 1_000_000_007)?
+            ),
+            (14, 1 | 2, ["--matrix", steps, modulus]) => print!(
+                "{}",
+                day_14::counts_mod("input/day_14.txt", steps.parse()?, modulus.parse()?)?
+            ),
+            (14, 1 | 2, ["--materialise", steps]) => {
+                print!(
+                    "{}",
+                    day_14::materialise("input/day_14.txt", steps.parse()?)?
+                )
+            }
+            (18, 1 | 2, ["--trace", a, b]) => print!("{}", day_18::trace(a, b)?),
+            (18, 2, ["--top", k]) => {
+                print!("{}", day_18::top_sums("input/day_18.txt", k.parse()?)?)
+            }
+            (19, 1 | 2, _) => print!(
+                "{}",
+                day_19::run_with_options("input/day_19.txt", problem, &opts)?
+            ),
+            (20, 1 | 2, ["--steps", steps]) => {
+                print!(
+                    "{}",
+                    day_20::lit_pixels_after("input/day_20.txt", steps.parse()?)?
+                )
+            }
+            (20, 1 | 2, ["--frames", dir, every]) => print!(
+                "{}",
+                day_20::write_frames(
+                    "input/day_20.txt",
+                    if problem == 1 { 2 } else { 50 },
+                    dir,
+                    every.parse()?
+                )?
+            ),
+            (21, 1 | 2, ["--players"]) => {
+                print!("{}", day_21::players("input/day_21.txt", problem)?)
+            }
+            (21, 2, ["--lengths"]) => print!("{}", day_21::game_lengths("input/day_21.txt")?),
+            (21, 2, ["--table"]) => print!("{}", day_21::win_table()?),
+            (21, 1 | 2, ["--rules", board_size, target, die_faces, rolls_per_turn]) => print!(
+                "{}",
+                day_21::play_with_rules(
+                    "input/day_21.txt",
+                    problem,
+                    board_size.parse()?,
+                    target.parse()?,
+                    die_faces.parse()?,
+                    rolls_per_turn.parse()?
+                )?
+            ),
+            (23, 1 | 2, ["--replay"]) => day_23::replay("input/day_23.txt", problem == 2, None)?,
+            (23, 1 | 2, ["--replay", delay_ms]) => {
+                day_23::replay("input/day_23.txt", problem == 2, Some(delay_ms.parse()?))?
+            }
+            (25, 1, ["--frames", every]) => {
+                print!(
+                    "{}",
+                    day_25::frames("input/day_25.txt", every.parse()?, None)?
+                )
+            }
+            (25, 1, ["--frames", dir, every]) => print!(
+                "{}",
+                day_25::frames("input/day_25.txt", every.parse()?, Some(dir))?
+            ),
+            _ => return Err(format!("{} are invalid options", opts.join(" ")).into()),
+        }
+        return Ok(());
+    }
     match day {
         1 => match problem {
             1 => println!("{}", day_1::prob_1("input/day_1.txt")?),